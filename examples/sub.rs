@@ -25,13 +25,15 @@ impl TryFrom<serde_json::Value> for Follow {
             .get("commit")
             .and_then(|v| v.get("record"))
             .and_then(|v| v.get("subject"))
-            .and_then(|v| v.as_str()).map(|v| Did::new(v.into()))
+            .and_then(|v| v.as_str())
+            .map(|v| Did::new(v.into()))
             .unwrap()
             .unwrap();
 
         let from = value
             .get("did")
-            .and_then(|v| v.as_str()).map(|v| Did::new(v.into()))
+            .and_then(|v| v.as_str())
+            .map(|v| Did::new(v.into()))
             .unwrap()
             .unwrap();
 
@@ -56,8 +58,6 @@ impl TryFrom<serde_json::Value> for Follow {
 }
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let bgs = "bsky.network";
-    // let (mut stream, _) = connect_async(format!("wss://{bgs}/xrpc/{NSID}")).await?;
     // louis' did:
     let did = "did:plc:p7gxyfr5vii5ntpwo7f6dhe2";
 
@@ -67,11 +67,10 @@ async fn main() -> Result<(), Box<dyn Error>> {
     while let Some(item) = stream.next().await {
         let item: serde_json::Value = serde_json::from_slice(&item.unwrap().into_data()).unwrap();
         let subject = &item["commit"]["record"]["subject"];
-        let origin = &item["did"];
         dbg!(&item["commit"]["collection"]);
         if &serde_json::Value::String(did.into()) == subject {
             let f: Follow = item.try_into().unwrap();
-            // println!("{:#?}", f);
+            println!("{:?} -> {:?}: {:?}", f.from, f.to, f.event);
         }
         // let subject = item["commit"]["record"]["subject"];
     }
@@ -1,32 +1,94 @@
+use std::io::{self, BufRead, Write};
 use std::path::PathBuf;
 
 use bsky_sdk::agent::config::FileStore;
 use bsky_sdk::BskyAgent;
-use clap::Parser;
+use clap::{Args as ClapArgs, Parser, Subcommand};
+use feed2block::ratelimit::RateLimited;
+use feed2block::session::{self, Credentials};
 use tracing::info;
 
-/// Generate config from auth.
+/// Generate config from auth, or check an existing one.
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// without a subcommand, logs in as `auth login` does (e.g. `auth -i foo.bsky.social`)
+    #[command(flatten)]
+    login: Option<LoginArgs>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// log in and save the session to the config
+    Login(LoginArgs),
+
+    /// load the config, refresh the session and print the account
+    #[command(alias = "whoami")]
+    Check {
+        /// config to check
+        #[arg(short, long, default_value = "config.json")]
+        config: PathBuf,
+    },
+}
+
+#[derive(ClapArgs, Debug)]
+struct LoginArgs {
     /// bsky handle (foo.bsky.social)
-    #[arg(short, long)]
+    #[arg(short, long, env)]
     identifier: String,
 
-    /// app password (not your account password!)
-    #[arg(short, long)]
-    app_password: String,
+    /// app password (not your account password!), prompted for if not set
+    #[arg(short, long, env, hide_env_values = true)]
+    app_password: Option<String>,
+
+    /// file containing the app password
+    #[arg(long, env, conflicts_with = "app_password")]
+    app_password_file: Option<PathBuf>,
+
+    /// 2FA token received by email, prompted for if required and not set
+    #[arg(long, env, hide_env_values = true)]
+    auth_factor_token: Option<String>,
 
     /// where to put the config
     #[arg(short, long, default_value = "config.json")]
     output: PathBuf,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
+/// Reads a line from stdin, used for the emailed 2FA token which isn't secret enough to hide.
+fn prompt(msg: &str) -> io::Result<String> {
+    print!("{msg}");
+    io::stdout().flush()?;
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim().to_string())
+}
+
+async fn login(args: LoginArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let mut credentials = match (args.app_password, args.app_password_file) {
+        (Some(password), _) => Credentials::new(args.identifier, password),
+        (None, Some(path)) => Credentials::from_file(args.identifier, &path)?,
+        (None, None) => {
+            let password = rpassword::prompt_password("app password: ")?;
+            Credentials::new(args.identifier, password)
+        }
+    };
+    if let Some(token) = args.auth_factor_token {
+        credentials = credentials.with_auth_factor_token(token);
+    }
+
     let agent = BskyAgent::builder().build().await?;
-    agent.login(args.identifier, args.app_password).await?;
+    if let Err(e) = credentials.login(&agent).await {
+        if !session::is_auth_factor_required(e.as_ref()) {
+            return Err(e);
+        }
+        let token = prompt("2FA token (check your emails): ")?;
+        credentials = credentials.with_auth_factor_token(token);
+        credentials.login(&agent).await?;
+    }
+
     info!(msg = "saving config", location = ?args.output);
     agent
         .to_config()
@@ -35,3 +97,28 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .await?;
     Ok(())
 }
+
+async fn check(config: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+    let agent = session::load_agent(RateLimited::default(), &config, None).await?;
+    session::refresh(&agent).await?;
+    let session = agent.get_session().await.ok_or("not logged in")?;
+
+    println!("did:    {}", session.data.did.as_str());
+    println!("handle: {}", session.data.handle.as_str());
+    println!("pds:    {}", agent.get_endpoint().await);
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tracing_subscriber::fmt::init();
+    let Args {
+        command,
+        login: args,
+    } = Args::parse();
+    match (command, args) {
+        (Some(Command::Login(args)), _) | (None, Some(args)) => login(args).await,
+        (Some(Command::Check { config }), _) => check(config).await,
+        (None, None) => Err("nothing to do: give a subcommand, or --identifier to log in".into()),
+    }
+}
@@ -1,36 +1,67 @@
+use atrium_api::agent::store::SessionStore;
 use atrium_api::types::string::AtIdentifier;
 use atrium_api::xrpc::XrpcClient;
 use atrium_api::{app::bsky::actor::get_profile, types::string::Did};
-use bsky_sdk::{
-    agent::config::{Config, FileStore},
-    BskyAgent,
-};
-use clap::{command, Parser};
-use feed2block::state::State;
+use bsky_sdk::BskyAgent;
+use clap::Parser;
+use feed2block::dedup::SeenSet;
+use feed2block::exempt::{ExemptArgs, Exemptions};
+use feed2block::label_server::LabelArgs;
+use feed2block::output::{Blocks, Deduped, Mutes};
+use feed2block::profile_filter::{ProfileFilter, ProfileFilterArgs};
+use feed2block::progress::Progress;
+use feed2block::resolver;
+use feed2block::session::{self, relogin, ReloginArgs};
+use feed2block::state::{State, StateStore};
+use feed2block::writer::{self, Priority, Writer};
 use feed2block::{
-    followers::from_followers, modlist::ModList, ratelimit::RateLimited, state::States,
-    subwatch::SubWatcher,
+    followers::{from_followers, from_follows, then_error, until_error},
+    list::{List, Purpose},
+    ratelimit::RateLimited,
+    subwatch::{SubWatcher, RECONNECT_DELAY},
+};
+use futures_util::{
+    future::{self, Either},
+    pin_mut, StreamExt, TryStreamExt,
 };
-use futures_util::{pin_mut, StreamExt};
-use std::fs::{File, OpenOptions};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::{error::Error, path::PathBuf};
-use tokio::sync::oneshot;
-use tokio::{select, signal, task};
+use tokio::{join, select, task, time};
+use tokio_tungstenite::tungstenite;
 use tokio_util::sync::CancellationToken;
 use tracing::{info, warn};
 
 const JETSTREAM_URL: &str = "wss://jetstream2.us-east.bsky.network/";
 
+/// Dids waiting to be written, per priority class.
+const WRITE_QUEUE_SIZE: usize = 1000;
+
+/// Time between two backfill progress logs.
+const PROGRESS_EVERY: Duration = Duration::from_secs(30);
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// bsky handle (foo.bsky.social)
+    /// account to watch: handle (foo.bsky.social), did or profile link
     #[arg(short, long, env)]
     account: String,
 
-    // modlist
-    #[arg(short, long, env)]
-    modlist: String,
+    /// list to add followers to: mod or curate list, AT-URI or bsky.app link, optional with --label, --block or --mute
+    #[arg(short, long, env, visible_alias = "modlist", short_alias = 'm')]
+    list: Option<String>,
+
+    /// also block them from our account
+    #[arg(long, default_value = "false")]
+    block: bool,
+
+    /// also mute them, privately
+    #[arg(long, default_value = "false")]
+    mute: bool,
+
+    /// add the accounts it follows instead of its followers
+    #[arg(long, default_value = "false")]
+    follows: bool,
 
     /// backfill
     #[arg(
@@ -41,18 +72,49 @@ struct Args {
     )]
     backfill: bool,
 
+    /// draw a progress bar while backfilling
+    #[arg(long, default_value = "false")]
+    progress_bar: bool,
+
     #[arg(short, long, default_value = "config.json")]
     config: PathBuf,
 
     #[arg(long, default_value = "cursor.json")]
     cursor: PathBuf,
+
+    #[command(flatten)]
+    relogin: ReloginArgs,
+
+    #[command(flatten)]
+    exempt: ExemptArgs,
+
+    #[command(flatten)]
+    profile: ProfileFilterArgs,
+
+    #[command(flatten)]
+    labels: LabelArgs,
+}
+
+/// Follows of the watched account, or follows made by it.
+async fn watch(did: &Did, follows: bool) -> Result<SubWatcher, tungstenite::Error> {
+    let jetstream = JETSTREAM_URL.parse().unwrap();
+    match follows {
+        true => SubWatcher::new_authored(jetstream, std::slice::from_ref(did)).await,
+        false => SubWatcher::new(jetstream, did.clone()).await,
+    }
 }
 
-async fn run_backfill<T: Send + Sync + XrpcClient>(
-    agent: &BskyAgent<T>,
+#[allow(clippy::too_many_arguments)]
+async fn run_backfill<T: Send + Sync + XrpcClient, S: SessionStore + Send + Sync>(
+    agent: &BskyAgent<T, S>,
     did: &Did,
-    did_state: &mut State,
-    last_added: Option<Did>,
+    did_state: State,
+    follows: bool,
+    states: &StateStore,
+    seen: &SeenSet,
+    profiles: &ProfileFilter,
+    writer: &Writer,
+    progress: &Progress,
 ) -> Result<(), Box<dyn Error>> {
     let last_cursor = match did_state.cursor() {
         Some(c) => {
@@ -65,156 +127,319 @@ async fn run_backfill<T: Send + Sync + XrpcClient>(
         }
     };
 
-    let follower_stream = from_followers(
-        &agent,
-        AtIdentifier::Did(did.clone()),
-        last_cursor.map(String::from), // could we accept Option<&str>?
-    )
-    .await
-    .map(|(f, cursor)| (f.did.clone(), cursor));
+    let actor = AtIdentifier::Did(did.clone());
+    let cursor = last_cursor.map(String::from); // could we accept Option<&str>?
+    let follower_stream = match follows {
+        true => Either::Right(from_follows(agent, actor, cursor).await),
+        false => Either::Left(from_followers(agent, actor, cursor).await),
+    }
+    .map_ok(|(f, cursor)| (f.did.clone(), cursor));
 
+    // stops at the first page or profiles that can't be read, returning the error once the
+    // pages before are written
+    let error = Mutex::new(None);
+    let follower_stream = until_error(follower_stream, &error);
     pin_mut!(follower_stream);
 
-    info!(
-        msg = "backfilling",
-        start_cursor = last_cursor,
-        last_added = ?last_added
-    );
-    let last_cursor = if let Some(last_added) = last_added {
-        did_state
-            .modlist
-            .add_stream_shortcircuit(&agent, follower_stream, last_added)
-            .await?
-    } else {
-        did_state
-            .modlist
-            .add_stream(&agent, follower_stream)
-            .await?
-    };
+    info!(msg = "backfilling", start_cursor = last_cursor);
 
-    if let Some(c) = last_cursor {
-        info!(msg = "writing last cursor", cursor = c);
-        did_state.set_cursor(c);
-    };
+    let follower_stream = follower_stream.inspect(|_| progress.inc_processed());
+    let follower_stream = seen
+        .mark(profiles.filter(agent, seen.filter(follower_stream)))
+        .inspect(|_| progress.inc_added());
+    let follower_stream = then_error(follower_stream, &error);
 
+    // saves the cursor once each page is fully written, to resume exactly from there
+    writer
+        .add_stream_checkpointed(Priority::Backfill, follower_stream, |c| {
+            info!(msg = "writing cursor", cursor = c);
+            states.set_cursor(did, c.to_string());
+            states.save()
+        })
+        .await?;
     Ok(())
 }
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     // install global collector configured based on RUST_LOG env var.
     tracing_subscriber::fmt::init();
     let Args {
         account,
-        modlist,
+        list: modlist,
+        block,
+        mute,
+        follows,
         backfill,
+        progress_bar,
         config,
         cursor,
+        relogin: relogin_args,
+        exempt,
+        profile,
+        labels,
     } = Args::parse();
+    if modlist.is_none() && labels.label().is_none() && !block && !mute {
+        return Err("nothing to write: give a --list, a --label, --block or --mute".into());
+    }
+    let profiles = ProfileFilter::from(profile);
 
     let token = CancellationToken::new();
 
-    info!(acc = account, modlist = modlist);
+    info!(
+        acc = account,
+        modlist = modlist,
+        label = labels.label(),
+        block = block,
+        mute = mute
+    );
+    let credentials = relogin_args.credentials(&config).await?;
     let client = RateLimited::default();
-    let agent = BskyAgent::builder()
-        .config(Config::load(&FileStore::new(config)).await.unwrap())
-        .client(client)
-        .build()
-        .await
-        .unwrap();
-
-    // load states
-    let mut states = match File::open(&cursor) {
-        Ok(r) => serde_json::from_reader(r)?,
-        Err(_) => States::new(),
+    let agent = session::load_agent(client, &config, credentials.as_ref()).await?;
+
+    // checks that the modlist exists and is ours before writing to it
+    let modlist = match modlist {
+        Some(m) => Some(
+            List::open_as(&agent, &m, &[Purpose::Mod, Purpose::Curate])
+                .await?
+                .into_list(),
+        ),
+        None => None,
     };
 
-    // get did of handle
-    let did = agent
+    // load states, each mode and set of outputs is backfilled on its own
+    let mut scope = vec![match follows {
+        true => "follows".to_string(),
+        false => "followers".to_string(),
+    }];
+    scope.extend(modlist.as_ref().map(|m| m.uri().to_string()));
+    scope.extend(labels.label().map(|l| format!("label:{l}")));
+    scope.extend(block.then(|| "block".to_string()));
+    scope.extend(mute.then(|| "mute".to_string()));
+    let states = StateStore::load(&cursor)?.with_scope(scope.join(" "));
+    // the only mode before scopes, its states were saved without one
+    let states = match !follows && modlist.is_some() && scope.len() == 2 {
+        true => states.with_unscoped_fallback(),
+        false => states,
+    };
+    let states = Arc::new(states);
+
+    // labels are signed by the logged-in account
+    let labeler = agent.get_session().await.ok_or("not logged in")?.data.did;
+    let labels = labels.open(labeler).await?;
+
+    // the owner, its friends and allowlisted accounts never get added
+    let exemptions = Arc::new(Exemptions::load(&agent, exempt.config(&agent).await?).await?);
+
+    let actor = resolver::resolve_actor(&agent, &account).await?;
+
+    // get profile of watched account
+    let profile = agent
         .api
         .app
         .bsky
         .actor
         .get_profile(get_profile::Parameters {
             data: get_profile::ParametersData {
-                actor: AtIdentifier::Handle(account.parse()?),
+                actor: AtIdentifier::Did(actor),
             },
             extra_data: ipld_core::ipld::Ipld::Null,
         })
-        .await?
-        .did
-        .clone();
-
-    // load state for did
-    let did_state =
-        states
-            .entry(did.clone())
-            .or_insert(State::new(ModList::new(modlist.clone()), None, None));
-
-    if backfill {
-        // get last added to modlist
-        let last_added = ModList::get_last_member(modlist.clone(), &agent)
-            .await
-            .map(|f| f.did);
-        info!(msg = "got last added", did = ?last_added);
-        run_backfill(&agent, &did, did_state, last_added).await?;
-        info!(msg = "backfilling done, writing state", state_path = ?cursor);
-
-        let w = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(&cursor)?;
-
-        // drop mutable ref to be able to write the state
-        let _ = did_state;
-        serde_json::to_writer(w, &states)?;
-    }
+        .await?;
+    let did = profile.did.clone();
+
+    // without a modlist, the state has no list
+    let state_list = modlist.clone().unwrap_or(List::new(String::new()));
+
+    // connect before backfilling so that follows happening meanwhile aren't missed
+    let event_stream = watch(&did, follows).await?;
+    info!(msg = "connected to event_stream", url = JETSTREAM_URL,);
+
+    // each output skips those already written to it, whether by a previous run or by hand,
+    // sources those written to all of them
+    let list_seen = match &modlist {
+        Some(m) => Some(SeenSet::from_list(m.uri().to_string(), &agent).await?),
+        None => None,
+    };
+    let labeled = labels.as_ref().map(|l| SeenSet::from_dids(l.labeled()));
+    let blocks = match block {
+        true => Some(Blocks::load(&agent).await?),
+        false => None,
+    };
+    let muted = match mute {
+        true => Some(SeenSet::from_dids(Mutes::muted(&agent).await?)),
+        false => None,
+    };
+    let blocked = blocks.as_ref().map(|b| SeenSet::from_dids(b.blocked()));
+    let seen = SeenSet::intersection(
+        &[&list_seen, &labeled, &blocked, &muted]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect::<Vec<_>>(),
+    );
+
+    let progress = Progress::new(
+        match follows {
+            true => profile.follows_count,
+            false => profile.followers_count,
+        }
+        .map(|c| c as u64),
+        Some(seen.len() as u64),
+    );
+    let progress = match progress_bar {
+        true => progress.with_bar(),
+        false => progress,
+    };
 
     let cloned_token = token.clone();
-    let (tx, rx) = oneshot::channel();
+    let task_states = states.clone();
+
+    // single writer for both phases, live follows first
+    let (writer, queue) = writer::channel(WRITE_QUEUE_SIZE);
+    let mut queue = queue
+        .with_exemptions(exemptions, exempt.refresh_period())
+        .with_seen(seen.clone());
 
     let jetstream = task::spawn(async move {
-        // load state for did
-        let did_state =
-            states
-                .entry(did.clone())
-                .or_insert(State::new(ModList::new(modlist), None, None));
-        let event_stream = SubWatcher::new(JETSTREAM_URL.parse().unwrap(), did).await;
-        info!(msg = "connected to event_stream", url = JETSTREAM_URL,);
-
-        let did_stream = event_stream.stream().await.map(|x| (x.from, None));
-
-        select! {
-            _ =
-                did_state
-                    .modlist
-                    .add_stream(&agent, did_stream)
-                     => {}
-            _ = cloned_token.cancelled() => {
-                info!(msg="got cancellation");
-                tx.send(states).unwrap()
+        let states = task_states;
+
+        let watch = async {
+            let mut event_stream = Some(event_stream);
+            loop {
+                let event_stream = match event_stream.take() {
+                    Some(s) => s,
+                    None => {
+                        time::sleep(RECONNECT_DELAY).await;
+                        match watch(&did, follows).await {
+                            Ok(s) => {
+                                info!(msg = "reconnected to event_stream", url = JETSTREAM_URL);
+                                s
+                            }
+                            Err(e) => {
+                                warn!(msg = "could not reconnect to event_stream", err = %e);
+                                continue;
+                            }
+                        }
+                    }
+                };
+
+                // in follows mode, unfollows carry no subject and are skipped
+                let did_stream = event_stream.stream().await.filter_map(|x| {
+                    let did = match follows {
+                        true => x.to().and_then(|to| to.parse().ok()),
+                        false => Some(x.from),
+                    };
+                    future::ready(did.map(|did| (did, ())))
+                });
+                let error = Mutex::new(None);
+                let did_stream = until_error(
+                    seen.mark(profiles.filter(&agent, seen.filter(did_stream))),
+                    &error,
+                );
+                pin_mut!(did_stream);
+                while let Some((did, _)) = did_stream.next().await {
+                    if let Err(e) = writer.add(Priority::Live, did).await {
+                        return Err(e.to_string());
+                    }
+                }
+                let error = error.lock().unwrap().take();
+                match error {
+                    Some(e) => warn!(msg = "could not get profiles, reconnecting", err = %e),
+                    None => warn!(msg = "event stream ended"),
+                }
             }
-        }
+        };
+
+        let backfill = async {
+            if !backfill {
+                return;
+            }
+            let report = async {
+                let mut interval = time::interval(PROGRESS_EVERY);
+                loop {
+                    interval.tick().await;
+                    progress.log();
+                }
+            };
+            let run = async {
+                loop {
+                    // re-read state on retries to resume from the last checkpoint
+                    let res = run_backfill(
+                        &agent,
+                        &did,
+                        states.get_or_insert(&did, state_list.clone()),
+                        follows,
+                        &states,
+                        &seen,
+                        &profiles,
+                        &writer,
+                        &progress,
+                    )
+                    .await
+                    .map_err(|e| {
+                        warn!(msg = "backfill failed", err = %e);
+                        session::is_auth_error(e.as_ref())
+                    });
+                    match res {
+                        Err(true) if relogin(&agent, credentials.as_ref()).await => continue,
+                        Err(_) => return false,
+                        Ok(()) => return true,
+                    }
+                }
+            };
+            let done = select! {
+                done = run => done,
+                _ = report => unreachable!(),
+            };
+            progress.finish();
+            if !done {
+                return;
+            }
+            info!(
+                msg = "backfilling done, writing state",
+                nb_seen = seen.len()
+            );
+            // followers come newest first: the next backfill starts over to get the new ones
+            states.clear_cursor(&did);
+            if let Err(e) = states.save() {
+                warn!(msg = "could not write state", err = %e);
+            }
+        };
+
+        // a failed write is retried on all of them, skipping what was already written
+        let output = (
+            (
+                labels
+                    .as_ref()
+                    .zip(labeled)
+                    .map(|(l, s)| Deduped::new(l, s)),
+                mute.then_some(Mutes),
+            ),
+            (
+                modlist
+                    .as_ref()
+                    .zip(list_seen)
+                    .map(|(m, s)| Deduped::new(m, s)),
+                blocks,
+            ),
+        );
+
+        let sources = async { join!(watch, backfill).0 };
+        queue
+            .serve(
+                &agent,
+                &output,
+                credentials.as_ref(),
+                &cloned_token,
+                sources,
+            )
+            .await
     });
 
-    match signal::ctrl_c().await {
-        Ok(()) => {
-            token.cancel();
-            let w = OpenOptions::new()
-                .write(true)
-                .truncate(true)
-                .create(true)
-                .open(cursor)?;
-
-            let states = rx.await?;
-            info!(msg = "got states back", "states"=?&states);
-            serde_json::to_writer(w, &states)?;
-            info!(msg = "shutting down!")
-        }
-        Err(err) => {
-            eprintln!("Unable to listen for shutdown signal: {}", err);
-            // we also shut down in case of error
-        }
-    }
-    Ok(())
+    let res = writer::until_ctrl_c(jetstream, &token).await;
+    info!(msg = "writing states", states = ?&states);
+    states.save()?;
+    info!(msg = "shutting down!");
+    res
 }
@@ -0,0 +1,363 @@
+use atrium_api::agent::store::SessionStore;
+use atrium_api::app::bsky::graph::get_relationships;
+use atrium_api::types::string::{AtIdentifier, Did};
+use atrium_api::xrpc::XrpcClient;
+use bsky_sdk::BskyAgent;
+use clap::Parser;
+use feed2block::dedup::SeenSet;
+use feed2block::exempt::{ExemptArgs, Exemptions};
+use feed2block::resolver;
+use feed2block::session::{self, relogin, ReloginArgs};
+use feed2block::state::StateStore;
+use feed2block::subwatch::Event;
+use feed2block::threshold::{Change, Rule, Scores, Watched};
+use feed2block::writer::{self, Priority, Writer};
+use feed2block::{
+    followers::from_followers,
+    list::{List, Purpose},
+    ratelimit::RateLimited,
+    subwatch::{SubWatcher, RECONNECT_DELAY},
+};
+use futures_util::{pin_mut, StreamExt};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::{error::Error, path::PathBuf};
+use tokio::{join, task, time};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+const JETSTREAM_URL: &str = "wss://jetstream2.us-east.bsky.network/";
+
+/// Dids waiting to be written, per priority class.
+const WRITE_QUEUE_SIZE: usize = 1000;
+
+/// Pages of followers read between two checkpoints, scores are costly to save.
+const CHECKPOINT_EVERY: usize = 20;
+
+/// Max number of accounts in a single `getRelationships` call.
+const MAX_RELATIONSHIPS: usize = 30;
+
+/// Adds accounts following enough of the watched accounts to a list,
+/// and removes them once they don't anymore.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// account to watch, with an optional weight (1 by default): foo.bsky.social=2
+    #[arg(short, long, required = true)]
+    account: Vec<Watched>,
+
+    /// score to reach: with default weights, number of watched accounts to follow
+    #[arg(short, long)]
+    threshold: f64,
+
+    /// list to add followers to: mod or curate list, AT-URI or bsky.app link
+    #[arg(short, long, env, visible_alias = "modlist", short_alias = 'm')]
+    list: String,
+
+    /// backfills the followers of every watched account
+    #[arg(short, long, default_value = "false")]
+    backfill: bool,
+
+    #[arg(short, long, default_value = "config.json")]
+    config: PathBuf,
+
+    #[arg(long, default_value = "cluster.json")]
+    cursor: PathBuf,
+
+    /// watched accounts followed by each account
+    #[arg(long, default_value = "scores.json")]
+    scores: PathBuf,
+
+    #[command(flatten)]
+    relogin: ReloginArgs,
+
+    #[command(flatten)]
+    exempt: ExemptArgs,
+}
+
+/// Queues the write matching a score change.
+async fn apply(
+    change: Option<Change>,
+    did: Did,
+    priority: Priority,
+    seen: &SeenSet,
+    writer: &Writer,
+) -> Result<(), Box<dyn Error>> {
+    match change {
+        Some(Change::Add) if seen.insert(&did) => {
+            info!(msg = "threshold reached", did = ?did);
+            writer.add(priority, did).await
+        }
+        Some(Change::Remove) if seen.remove(&did) => {
+            info!(msg = "below threshold", did = ?did);
+            writer.remove(priority, did).await
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Watched accounts that did still follows.
+async fn followed<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+    agent: &BskyAgent<T, S>,
+    did: &Did,
+    accounts: &[Did],
+) -> Result<HashSet<Did>, Box<dyn Error>> {
+    let mut followed = HashSet::new();
+    for chunk in accounts.chunks(MAX_RELATIONSHIPS) {
+        let relationships = agent
+            .api
+            .app
+            .bsky
+            .graph
+            .get_relationships(get_relationships::Parameters {
+                data: get_relationships::ParametersData {
+                    actor: AtIdentifier::Did(did.clone()),
+                    others: Some(chunk.iter().cloned().map(AtIdentifier::Did).collect()),
+                },
+                extra_data: ipld_core::ipld::Ipld::Null,
+            })
+            .await?
+            .data
+            .relationships;
+        followed.extend(relationships.into_iter().filter_map(|r| match r {
+            atrium_api::types::Union::Refs(
+                get_relationships::OutputRelationshipsItem::AppBskyGraphDefsRelationship(r),
+            ) if r.following.is_some() => Some(r.data.did),
+            _ => None,
+        }));
+    }
+    Ok(followed)
+}
+
+async fn run_backfill<T: Send + Sync + XrpcClient, S: SessionStore + Send + Sync>(
+    agent: &BskyAgent<T, S>,
+    watched: &Did,
+    modlist: &List,
+    states: &StateStore,
+    scores: &Scores,
+    seen: &SeenSet,
+    writer: &Writer,
+) -> Result<(), Box<dyn Error>> {
+    let state = states.get_or_insert(watched, modlist.clone());
+    info!(msg = "backfilling", account = ?watched, start_cursor = state.cursor());
+
+    let follower_stream = from_followers(
+        agent,
+        AtIdentifier::Did(watched.clone()),
+        state.cursor().map(String::from),
+    )
+    .await;
+    pin_mut!(follower_stream);
+
+    // few followers reach the threshold: checkpoint on pages read, not only on writes
+    let mut last_cursor: Option<String> = None;
+    let mut pages: usize = 0;
+    while let Some(item) = follower_stream.next().await {
+        let (follower, cursor) = item?;
+        if let Some(c) = cursor {
+            if let Some(done) = last_cursor.as_deref().filter(|l| *l != c) {
+                pages += 1;
+                if pages.is_multiple_of(CHECKPOINT_EVERY) {
+                    checkpoint(watched, done, states, scores, writer).await?;
+                }
+            }
+            last_cursor = Some(c);
+        }
+        let change = scores.follow(&follower.did, watched);
+        apply(
+            change,
+            follower.did.clone(),
+            Priority::Backfill,
+            seen,
+            writer,
+        )
+        .await?;
+    }
+    match &last_cursor {
+        Some(c) => checkpoint(watched, c, states, scores, writer).await,
+        None => scores.save(),
+    }
+}
+
+/// Saves scores then the cursor, once the page before it is written:
+/// a resumed backfill reads some follows twice at worst.
+async fn checkpoint(
+    watched: &Did,
+    cursor: &str,
+    states: &StateStore,
+    scores: &Scores,
+    writer: &Writer,
+) -> Result<(), Box<dyn Error>> {
+    writer.flush(Priority::Backfill).await?;
+    scores.save()?;
+    info!(msg = "writing cursor", account = ?watched, cursor = cursor);
+    states.set_cursor(watched, cursor.to_string());
+    states.save()
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    tracing_subscriber::fmt::init();
+    let Args {
+        account,
+        threshold,
+        list: modlist,
+        backfill,
+        config,
+        cursor,
+        scores,
+        relogin: relogin_args,
+        exempt,
+    } = Args::parse();
+
+    let token = CancellationToken::new();
+
+    let credentials = relogin_args.credentials(&config).await?;
+    let client = RateLimited::default();
+    let agent = session::load_agent(client, &config, credentials.as_ref()).await?;
+
+    let states = Arc::new(StateStore::load(&cursor)?);
+
+    // checks that the modlist exists and is ours before writing to it
+    let modlist = List::open_as(&agent, &modlist, &[Purpose::Mod, Purpose::Curate])
+        .await?
+        .into_list();
+
+    let exemptions = Arc::new(Exemptions::load(&agent, exempt.config(&agent).await?).await?);
+
+    let mut rule = Rule::new(threshold);
+    for Watched { account, weight } in account {
+        let did = resolver::resolve_actor(&agent, &account).await?;
+        info!(msg = "watching", account = account, did = ?did, weight = weight);
+        rule = rule.watch(did, weight);
+    }
+    let accounts: Vec<Did> = rule.accounts().cloned().collect();
+    let scores = Arc::new(Scores::load(&scores, rule)?);
+
+    // connect before backfilling so that follows happening meanwhile aren't missed
+    let event_stream =
+        SubWatcher::new_many(JETSTREAM_URL.parse().unwrap(), accounts.clone()).await?;
+    info!(msg = "connected to event_stream", url = JETSTREAM_URL);
+
+    // skip current members, whether added by a previous run or by hand
+    let seen = SeenSet::from_list(modlist.uri().to_string(), &agent).await?;
+
+    let cloned_token = token.clone();
+    let task_states = states.clone();
+    let task_scores = scores.clone();
+
+    let (writer, queue) = writer::channel(WRITE_QUEUE_SIZE);
+    let mut queue = queue
+        .with_exemptions(exemptions, exempt.refresh_period())
+        .with_seen(seen.clone());
+
+    let jetstream = task::spawn(async move {
+        let states = task_states;
+        let scores = task_scores;
+
+        // accounts that reached the threshold in a previous run, but weren't written
+        let reconcile = async {
+            for did in scores.matching() {
+                if seen.insert(&did) && writer.add(Priority::Reconcile, did).await.is_err() {
+                    return;
+                }
+            }
+        };
+
+        let watch = async {
+            let mut event_stream = Some(event_stream);
+            loop {
+                let event_stream = match event_stream.take() {
+                    Some(s) => s,
+                    None => {
+                        time::sleep(RECONNECT_DELAY).await;
+                        match SubWatcher::new_many(JETSTREAM_URL.parse().unwrap(), accounts.clone())
+                            .await
+                        {
+                            Ok(s) => {
+                                info!(msg = "reconnected to event_stream", url = JETSTREAM_URL);
+                                s
+                            }
+                            Err(e) => {
+                                warn!(msg = "could not reconnect to event_stream", err = %e);
+                                continue;
+                            }
+                        }
+                    }
+                };
+
+                let follows = event_stream.stream().await;
+                pin_mut!(follows);
+                while let Some(follow) = follows.next().await {
+                    let change = match follow.event() {
+                        Event::Follow => {
+                            let Some(to) = follow.to().and_then(|to| to.parse::<Did>().ok()) else {
+                                continue;
+                            };
+                            scores.follow(&follow.from, &to)
+                        }
+                        // the subject is unknown, ask which watched accounts are still followed
+                        Event::Unfollow if scores.is_tracked(&follow.from) => {
+                            let res = followed(&agent, &follow.from, &accounts)
+                                .await
+                                .map_err(|e| e.to_string());
+                            match res {
+                                Ok(followed) => scores.set(&follow.from, followed),
+                                Err(e) => {
+                                    warn!(msg = "could not get relationships", did = ?follow.from, err = e);
+                                    continue;
+                                }
+                            }
+                        }
+                        Event::Unfollow => continue,
+                    };
+                    let res = apply(change, follow.from, Priority::Live, &seen, &writer).await;
+                    if let Err(e) = res {
+                        return Err(e.to_string());
+                    }
+                }
+                warn!(msg = "event stream ended");
+            }
+        };
+
+        let backfill = async {
+            if !backfill {
+                return;
+            }
+            for watched in &accounts {
+                loop {
+                    let res =
+                        run_backfill(&agent, watched, &modlist, &states, &scores, &seen, &writer)
+                            .await
+                            .map_err(|e| {
+                                warn!(msg = "backfill failed", account = ?watched, err = %e);
+                                session::is_auth_error(e.as_ref())
+                            });
+                    match res {
+                        Err(true) if relogin(&agent, credentials.as_ref()).await => continue,
+                        Err(_) => return,
+                        Ok(()) => break,
+                    }
+                }
+            }
+            info!(msg = "backfilling done", nb_seen = seen.len());
+        };
+
+        let sources = async { join!(reconcile, watch, backfill).1 };
+        queue
+            .serve(
+                &agent,
+                &modlist,
+                credentials.as_ref(),
+                &cloned_token,
+                sources,
+            )
+            .await
+    });
+
+    let res = writer::until_ctrl_c(jetstream, &token).await;
+    states.save()?;
+    scores.save()?;
+    info!(msg = "shutting down!");
+    res
+}
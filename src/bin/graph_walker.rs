@@ -0,0 +1,197 @@
+use atrium_api::{agent::store::SessionStore, types::string::Did, xrpc::XrpcClient};
+use bsky_sdk::BskyAgent;
+use clap::Parser;
+use feed2block::dedup::SeenSet;
+use feed2block::exempt::{ExemptArgs, Exemptions};
+use feed2block::graph::{from_graph, Limits};
+use feed2block::session::{self, relogin, ReloginArgs};
+use feed2block::state::StateStore;
+use feed2block::writer::{self, Priority, Writer};
+use feed2block::{
+    list::{List, Purpose},
+    ratelimit::RateLimited,
+    resolver,
+};
+use futures_util::{pin_mut, StreamExt};
+use std::sync::Arc;
+use std::{error::Error, path::PathBuf};
+use tokio::task;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// Dids waiting to be written, per priority class.
+const WRITE_QUEUE_SIZE: usize = 1000;
+
+/// Pages of followers read between two checkpoints.
+const CHECKPOINT_EVERY: usize = 20;
+
+/// Adds followers of an account, their followers and so on, to a list.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// account to start from: handle (foo.bsky.social), did or profile link
+    #[arg(short, long, env)]
+    account: String,
+
+    /// list to add followers to: mod or curate list, AT-URI or bsky.app link
+    #[arg(short, long, env, visible_alias = "modlist", short_alias = 'm')]
+    list: String,
+
+    /// 1 for followers only, 2 for followers of followers...
+    #[arg(short, long, default_value = "2")]
+    depth: usize,
+
+    /// followers read from each account at most
+    #[arg(long, default_value = "1000")]
+    per_node: usize,
+
+    /// followers read overall at most
+    #[arg(long, default_value = "100000")]
+    total: usize,
+
+    #[arg(short, long, default_value = "config.json")]
+    config: PathBuf,
+
+    /// progress of the walk, use a file per walk
+    #[arg(long, default_value = "graph.json")]
+    cursor: PathBuf,
+
+    #[command(flatten)]
+    relogin: ReloginArgs,
+
+    #[command(flatten)]
+    exempt: ExemptArgs,
+}
+
+/// Saves the walk once what was read before has been written:
+/// a resumed walk reads some followers twice at worst.
+async fn checkpoint(states: &StateStore, writer: &Writer) -> Result<(), Box<dyn Error>> {
+    writer.flush(Priority::Backfill).await?;
+    states.save()
+}
+
+/// Writes the walk, from where `states` left it.
+async fn run_walk<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+    agent: &BskyAgent<T, S>,
+    root: Did,
+    modlist: List,
+    limits: Limits,
+    states: &StateStore,
+    seen: &SeenSet,
+    writer: &Writer,
+) -> Result<(), Box<dyn Error>> {
+    let discovered = from_graph(agent, root, modlist, limits, states);
+    pin_mut!(discovered);
+
+    let mut page = None;
+    let mut pages: usize = 0;
+    while let Some(d) = discovered.next().await {
+        let d = d?;
+        let current = (d.path.last().cloned(), d.cursor.clone());
+        if page.as_ref().is_some_and(|p| *p != current) {
+            pages += 1;
+            if pages.is_multiple_of(CHECKPOINT_EVERY) {
+                checkpoint(states, writer).await?;
+            }
+        }
+        page = Some(current);
+
+        if !seen.insert(&d.did) {
+            continue;
+        }
+        let path: Vec<&str> = d.path.iter().map(|did| did.as_str()).collect();
+        info!(msg = "adding", did = ?d.did, depth = d.depth(), path = ?path);
+        writer.add(Priority::Backfill, d.did).await?;
+    }
+    checkpoint(states, writer).await?;
+    info!(msg = "walk written", nb_seen = seen.len());
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    tracing_subscriber::fmt::init();
+    let Args {
+        account,
+        list: modlist,
+        depth,
+        per_node,
+        total,
+        config,
+        cursor,
+        relogin: relogin_args,
+        exempt,
+    } = Args::parse();
+    let limits = Limits {
+        depth,
+        per_node,
+        total,
+    };
+
+    let token = CancellationToken::new();
+
+    let credentials = relogin_args.credentials(&config).await?;
+    let client = RateLimited::default();
+    let agent = session::load_agent(client, &config, credentials.as_ref()).await?;
+
+    let states = Arc::new(StateStore::load(&cursor)?);
+
+    // checks that the modlist exists and is ours before writing to it
+    let modlist = List::open_as(&agent, &modlist, &[Purpose::Mod, Purpose::Curate])
+        .await?
+        .into_list();
+
+    let exemptions = Arc::new(Exemptions::load(&agent, exempt.config(&agent).await?).await?);
+
+    let root = resolver::resolve_actor(&agent, &account).await?;
+    info!(msg = "walking", root = ?root, limits = ?limits);
+
+    // skip current members, whether added by a previous run or by hand
+    let seen = SeenSet::from_list(modlist.uri().to_string(), &agent).await?;
+
+    let cloned_token = token.clone();
+    let task_states = states.clone();
+
+    let (writer, queue) = writer::channel(WRITE_QUEUE_SIZE);
+    let mut queue = queue
+        .with_exemptions(exemptions, exempt.refresh_period())
+        .with_seen(seen.clone());
+
+    let walker = task::spawn(async move {
+        let states = task_states;
+
+        let walk = async {
+            let res = loop {
+                // resumes where the walk stopped once logged in again, errors aren't Send
+                let res = run_walk(
+                    &agent,
+                    root.clone(),
+                    modlist.clone(),
+                    limits,
+                    &states,
+                    &seen,
+                    &writer,
+                )
+                .await
+                .map_err(|e| (session::is_auth_error(e.as_ref()), e.to_string()));
+                match res {
+                    Err((true, _)) if relogin(&agent, credentials.as_ref()).await => continue,
+                    res => break res.map_err(|(_, e)| e),
+                }
+            };
+            if let Err(e) = &res {
+                warn!(msg = "walk failed", err = e);
+            }
+            writer.metrics().log();
+            res
+        };
+        queue
+            .serve(&agent, &modlist, credentials.as_ref(), &cloned_token, walk)
+            .await
+    });
+
+    let res = writer::until_ctrl_c(walker, &token).await;
+    // pages read since the last checkpoint are read again on resume
+    info!(msg = "shutting down!");
+    res
+}
@@ -0,0 +1,267 @@
+use atrium_api::types::string::Did;
+use chrono::Utc;
+use clap::Parser;
+use feed2block::dedup::SeenSet;
+use feed2block::exempt::{ExemptArgs, Exemptions};
+use feed2block::followers::until_error;
+use feed2block::labeler::{query_labels, resolve_endpoint, subscribe_labels, Labels};
+use feed2block::session::{self, ReloginArgs};
+use feed2block::state::StateStore;
+use feed2block::threshold::Change;
+use feed2block::writer::{self, Priority, Writer};
+use feed2block::{
+    list::{List, Purpose},
+    ratelimit::RateLimited,
+    resolver,
+};
+use futures_util::{pin_mut, StreamExt};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{error::Error, path::PathBuf};
+use tokio::{select, task, time};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// Dids waiting to be written, per priority class.
+const WRITE_QUEUE_SIZE: usize = 1000;
+
+/// Label frames read between two checkpoints.
+const CHECKPOINT_EVERY: usize = 100;
+
+/// Wait before querying labels or reconnecting to the label stream again.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Time between two checks of expired labels.
+const EXPIRE_EVERY: Duration = Duration::from_secs(60);
+
+/// Adds accounts labeled by a labeler to a list, and removes them once the labels are negated or expired.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// labeler: handle, did or profile link
+    #[arg(short, long, env)]
+    labeler: String,
+
+    /// labeler endpoint, read from its did document by default
+    #[arg(long)]
+    endpoint: Option<String>,
+
+    /// label value to act on, any by default
+    #[arg(long)]
+    label: Vec<String>,
+
+    /// list to add labeled accounts to: mod or curate list, AT-URI or bsky.app link
+    #[arg(short = 'm', long, env, visible_alias = "modlist")]
+    list: String,
+
+    #[arg(short, long, default_value = "config.json")]
+    config: PathBuf,
+
+    /// sequence number of the last label written, per labeler
+    #[arg(long, default_value = "labels.json")]
+    cursor: PathBuf,
+
+    #[command(flatten)]
+    relogin: ReloginArgs,
+
+    #[command(flatten)]
+    exempt: ExemptArgs,
+}
+
+/// Queues the write matching a label change.
+async fn apply(
+    change: Option<(Did, Change)>,
+    priority: Priority,
+    seen: &SeenSet,
+    writer: &Writer,
+) -> Result<(), Box<dyn Error>> {
+    match change {
+        Some((did, Change::Add)) if seen.insert(&did) => {
+            info!(msg = "labeled", did = ?did);
+            writer.add(priority, did).await
+        }
+        Some((did, Change::Remove)) if seen.remove(&did) => {
+            info!(msg = "label negated", did = ?did);
+            writer.remove(priority, did).await
+        }
+        _ => Ok(()),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    tracing_subscriber::fmt::init();
+    let Args {
+        labeler,
+        endpoint,
+        label,
+        list: modlist,
+        config,
+        cursor,
+        relogin: relogin_args,
+        exempt,
+    } = Args::parse();
+
+    let token = CancellationToken::new();
+
+    let credentials = relogin_args.credentials(&config).await?;
+    let client = RateLimited::default();
+    let agent = session::load_agent(client, &config, credentials.as_ref()).await?;
+
+    let states = Arc::new(StateStore::load(&cursor)?);
+
+    // checks that the modlist exists and is ours before writing to it
+    let modlist = List::open_as(&agent, &modlist, &[Purpose::Mod, Purpose::Curate])
+        .await?
+        .into_list();
+
+    let exemptions = Arc::new(Exemptions::load(&agent, exempt.config(&agent).await?).await?);
+
+    let labeler = resolver::resolve_actor(&agent, &labeler).await?;
+    let endpoint = match endpoint {
+        Some(e) => e.parse()?,
+        None => resolve_endpoint(&labeler).await?,
+    };
+    info!(msg = "following labeler", did = ?labeler, endpoint = endpoint.as_str(), labels = ?label);
+    let mut labels = Labels::new(labeler.clone(), label);
+
+    let state = states.get_or_insert(&labeler, modlist.clone());
+    let seq = state.cursor().and_then(|c| c.parse::<i64>().ok());
+
+    // connect before backfilling so that labels sent meanwhile aren't missed
+    let live = subscribe_labels(&endpoint, seq).await?;
+    info!(msg = "connected to label stream", cursor = seq);
+
+    // skip current members, whether added by a previous run or by hand
+    let seen = SeenSet::from_list(modlist.uri().to_string(), &agent).await?;
+
+    let cloned_token = token.clone();
+    let task_states = states.clone();
+
+    let (writer, queue) = writer::channel(WRITE_QUEUE_SIZE);
+    let mut queue = queue
+        .with_exemptions(exemptions, exempt.refresh_period())
+        .with_seen(seen.clone());
+
+    let jetstream = task::spawn(async move {
+        let states = task_states;
+
+        let watch = async {
+            // labels currently applied, known only to the labeler, so that their negation is noticed
+            let mut cursor = None;
+            loop {
+                let error = Mutex::new(None);
+                let applied =
+                    until_error(query_labels(&endpoint, &labeler, cursor.clone()), &error);
+                pin_mut!(applied);
+                while let Some((label, next)) = applied.next().await {
+                    let change = labels.apply(&label);
+                    if apply(change, Priority::Backfill, &seen, &writer)
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                    cursor = next;
+                }
+                let error = error.lock().unwrap().take();
+                match error {
+                    Some(e) => warn!(msg = "could not query labels, retrying", err = %e),
+                    None => break,
+                }
+                time::sleep(RETRY_DELAY).await;
+            }
+            info!(msg = "backfilling done", nb_seen = seen.len());
+
+            let mut live = Some(live);
+            let mut last_seq = seq;
+            let mut frames: usize = 0;
+            let mut expiry = time::interval(EXPIRE_EVERY);
+            loop {
+                let stream = match live.take() {
+                    Some(s) => s,
+                    None => {
+                        time::sleep(RETRY_DELAY).await;
+                        // errors aren't Send, only keep their message
+                        let res = subscribe_labels(&endpoint, last_seq)
+                            .await
+                            .map_err(|e| e.to_string());
+                        match res {
+                            Ok(s) => {
+                                info!(msg = "reconnected to label stream", cursor = last_seq);
+                                s
+                            }
+                            Err(e) => {
+                                warn!(msg = "could not reconnect to label stream", err = e);
+                                continue;
+                            }
+                        }
+                    }
+                };
+                pin_mut!(stream);
+                loop {
+                    let (label, seq) = select! {
+                        item = stream.next() => match item {
+                            Some(item) => item,
+                            None => break,
+                        },
+                        _ = expiry.tick() => {
+                            for change in labels.expire(Utc::now()) {
+                                if apply(Some(change), Priority::Live, &seen, &writer)
+                                    .await
+                                    .is_err()
+                                {
+                                    return;
+                                }
+                            }
+                            continue;
+                        }
+                    };
+                    if let Some(done) = last_seq.filter(|l| *l != seq) {
+                        frames += 1;
+                        if frames.is_multiple_of(CHECKPOINT_EVERY) {
+                            // saves the sequence number once the labels before it are written
+                            let res = writer
+                                .flush(Priority::Live)
+                                .await
+                                .map_err(|e| e.to_string());
+                            if res.is_err() {
+                                return;
+                            }
+                            states.set_cursor(&labeler, done.to_string());
+                            if let Err(e) = states.save().map_err(|e| e.to_string()) {
+                                warn!(msg = "could not save states", err = e);
+                            }
+                        }
+                    }
+                    last_seq = Some(seq);
+                    let change = labels.apply(&label);
+                    if apply(change, Priority::Live, &seen, &writer).await.is_err() {
+                        return;
+                    }
+                }
+                // also on error frames, e.g. when too slow: every frame read was queued
+                warn!(msg = "label stream ended, reconnecting from the last label read");
+            }
+        };
+
+        // only returns once queuing failed
+        let sources = async {
+            watch.await;
+            Err("label stream stopped".to_string())
+        };
+        queue
+            .serve(
+                &agent,
+                &modlist,
+                credentials.as_ref(),
+                &cloned_token,
+                sources,
+            )
+            .await
+    });
+
+    let res = writer::until_ctrl_c(jetstream, &token).await;
+    info!(msg = "shutting down!");
+    res
+}
@@ -0,0 +1,230 @@
+use clap::Parser;
+use feed2block::dedup::SeenSet;
+use feed2block::exempt::{ExemptArgs, Exemptions};
+use feed2block::mirror::{Mirror, Update};
+use feed2block::session::{self, ReloginArgs};
+use feed2block::subwatch::{ListItemWatcher, RECONNECT_DELAY};
+use feed2block::writer::{self, Priority};
+use feed2block::{
+    list::{List, Purpose},
+    ratelimit::RateLimited,
+    resolver,
+};
+use futures_util::{pin_mut, StreamExt};
+use std::sync::Arc;
+use std::{error::Error, path::PathBuf};
+use tokio::{join, task, time};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+const JETSTREAM_URL: &str = "wss://jetstream2.us-east.bsky.network/";
+
+/// Dids waiting to be written, per priority class.
+const WRITE_QUEUE_SIZE: usize = 1000;
+
+/// Keeps a list in sync with the members of another list.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// list to mirror, anyone's: AT-URI or bsky.app link
+    #[arg(short, long, env)]
+    source: String,
+
+    /// list to add its members to: mod or curate list, AT-URI or bsky.app link
+    #[arg(short, long, env, visible_alias = "modlist", short_alias = 'm')]
+    list: String,
+
+    /// also remove accounts removed from the source list, even if they were added another way
+    #[arg(long, default_value = "false")]
+    remove: bool,
+
+    /// items of the source list, to notice removals that happened while not running
+    #[arg(long, default_value = "mirror.json")]
+    snapshot: PathBuf,
+
+    #[arg(short, long, default_value = "config.json")]
+    config: PathBuf,
+
+    #[command(flatten)]
+    relogin: ReloginArgs,
+
+    #[command(flatten)]
+    exempt: ExemptArgs,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    tracing_subscriber::fmt::init();
+    let Args {
+        source,
+        list: modlist,
+        remove,
+        snapshot,
+        config,
+        relogin: relogin_args,
+        exempt,
+    } = Args::parse();
+
+    let token = CancellationToken::new();
+
+    let credentials = relogin_args.credentials(&config).await?;
+    let client = RateLimited::default();
+    let agent = session::load_agent(client, &config, credentials.as_ref()).await?;
+
+    // checks that the modlist exists and is ours before writing to it
+    let modlist = List::open_as(&agent, &modlist, &[Purpose::Mod, Purpose::Curate])
+        .await?
+        .into_list();
+
+    let exemptions = Arc::new(Exemptions::load(&agent, exempt.config(&agent).await?).await?);
+
+    let source = resolver::resolve_list(&agent, &source).await?;
+    let owner = List::get_view(source.clone(), &agent)
+        .await?
+        .creator
+        .data
+        .did;
+    if source == modlist.uri() {
+        return Err("a list can't mirror itself".into());
+    }
+
+    // connect before reading the source so that changes happening meanwhile aren't missed
+    let event_stream = ListItemWatcher::new(JETSTREAM_URL.parse().unwrap(), &owner).await?;
+    info!(msg = "connected to event_stream", url = JETSTREAM_URL, owner = ?owner);
+
+    let (mirror, removed) = Mirror::load(&agent, source, &snapshot).await?;
+    let mirror = Arc::new(mirror);
+
+    // skip current members, whether added by a previous run or by hand
+    let seen = SeenSet::from_list(modlist.uri().to_string(), &agent).await?;
+
+    let cloned_token = token.clone();
+    let task_mirror = mirror.clone();
+
+    let (writer, queue) = writer::channel(WRITE_QUEUE_SIZE);
+    let mut queue = queue
+        .with_exemptions(exemptions, exempt.refresh_period())
+        .with_seen(seen.clone());
+
+    let jetstream = task::spawn(async move {
+        let mirror = task_mirror;
+
+        let apply = |update: Update, priority: Priority| {
+            let writer = &writer;
+            let seen = &seen;
+            async move {
+                match update {
+                    Update::Add(did) if seen.insert(&did) => {
+                        info!(msg = "adding", class = %priority, did = ?did);
+                        writer.add(priority, did).await
+                    }
+                    Update::Remove(did) if remove && seen.remove(&did) => {
+                        info!(msg = "removing", class = %priority, did = ?did);
+                        writer.remove(priority, did).await
+                    }
+                    _ => Ok(()),
+                }
+            }
+        };
+
+        // members added, or removed, while not running
+        let reconcile = async {
+            let updates = mirror
+                .members()
+                .into_iter()
+                .map(Update::Add)
+                .chain(removed.into_iter().map(Update::Remove));
+            for update in updates {
+                if apply(update, Priority::Reconcile).await.is_err() {
+                    return;
+                }
+            }
+            // removals stay in the snapshot until written, to be retried on the next start
+            let flushed = writer
+                .flush(Priority::Reconcile)
+                .await
+                .map_err(|e| e.to_string());
+            match flushed {
+                Ok(()) => {
+                    mirror.removed_written();
+                    if let Err(e) = mirror.save() {
+                        warn!(msg = "could not save snapshot", err = %e);
+                    }
+                    info!(msg = "source list synced", nb_seen = seen.len());
+                }
+                Err(e) => warn!(msg = "source list not fully synced", err = e),
+            }
+        };
+
+        let watch = async {
+            let mut event_stream = Some(event_stream);
+            loop {
+                let event_stream = match event_stream.take() {
+                    Some(s) => s,
+                    None => {
+                        time::sleep(RECONNECT_DELAY).await;
+                        match ListItemWatcher::new(JETSTREAM_URL.parse().unwrap(), &owner).await {
+                            Ok(s) => {
+                                info!(msg = "reconnected to event_stream", url = JETSTREAM_URL);
+                                s
+                            }
+                            Err(e) => {
+                                warn!(msg = "could not reconnect to event_stream", err = %e);
+                                continue;
+                            }
+                        }
+                    }
+                };
+
+                let events = event_stream.stream().await;
+                pin_mut!(events);
+                while let Some(event) = events.next().await {
+                    let Some(update) = mirror.update(&event) else {
+                        mirror.apply(event);
+                        continue;
+                    };
+                    let removal = matches!(update, Update::Remove(_));
+                    if let Err(e) = apply(update, Priority::Live).await {
+                        return Err(e.to_string());
+                    }
+                    // like on reconcile, a removal stays in the snapshot until written,
+                    // to be noticed again on the next start
+                    if removal {
+                        let flushed = writer
+                            .flush(Priority::Live)
+                            .await
+                            .map_err(|e| e.to_string());
+                        if let Err(e) = flushed {
+                            warn!(
+                                msg = "removal not written, keeping it in the snapshot",
+                                err = e
+                            );
+                            continue;
+                        }
+                    }
+                    mirror.apply(event);
+                    if let Err(e) = mirror.save() {
+                        warn!(msg = "could not save snapshot", err = %e);
+                    }
+                }
+                warn!(msg = "event stream ended");
+            }
+        };
+
+        let sources = async { join!(reconcile, watch).1 };
+        queue
+            .serve(
+                &agent,
+                &modlist,
+                credentials.as_ref(),
+                &cloned_token,
+                sources,
+            )
+            .await
+    });
+
+    let res = writer::until_ctrl_c(jetstream, &token).await;
+    mirror.save()?;
+    info!(msg = "shutting down!");
+    res
+}
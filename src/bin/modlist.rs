@@ -0,0 +1,277 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::{error::Error, path::PathBuf};
+
+use atrium_api::agent::store::SessionStore;
+use atrium_api::types::string::Did;
+use atrium_api::xrpc::XrpcClient;
+use bsky_sdk::BskyAgent;
+use clap::{Parser, Subcommand, ValueEnum};
+use feed2block::{
+    exempt::{ExemptArgs, Exemptions},
+    list::{List, Purpose},
+    output::MAX_WRITES,
+    ratelimit::RateLimited,
+    resolver, session,
+    state::StateStore,
+    writer::{self, Priority, Writer},
+};
+use futures_util::{pin_mut, StreamExt, TryStreamExt};
+use tokio::select;
+use tracing::{info, warn};
+
+/// Batches waiting to be written.
+const WRITE_QUEUE_SIZE: usize = 1;
+
+/// Create, describe, export and import lists.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[arg(short, long, default_value = "config.json")]
+    config: PathBuf,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// create a new list and print its uri
+    Create {
+        #[arg(short, long)]
+        name: String,
+
+        #[arg(short, long)]
+        description: Option<String>,
+
+        #[arg(short, long, value_enum, default_value_t = Purpose::Mod)]
+        purpose: Purpose,
+    },
+
+    /// print name, purpose and number of members of a list
+    Show { list: String },
+
+    /// write the members of a list to a file (or stdout)
+    Export {
+        list: String,
+
+        #[arg(short, long, value_enum, default_value_t = Format::Csv)]
+        format: Format,
+
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// add accounts from a file (one handle, did or profile link per line) to a list
+    Import {
+        list: String,
+        input: PathBuf,
+
+        /// lines of the input already written, to resume an interrupted import
+        #[arg(long, default_value = "import.json")]
+        cursor: PathBuf,
+
+        #[command(flatten)]
+        exempt: ExemptArgs,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Format {
+    Csv,
+    Json,
+}
+
+/// Quotes a csv field if needed.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+async fn export<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+    agent: &BskyAgent<T, S>,
+    list: String,
+    format: Format,
+    output: Option<PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    let mut w: Box<dyn Write> = match output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout().lock()),
+    };
+
+    let members = List::get_members(list, agent, None).await;
+    pin_mut!(members);
+    match format {
+        Format::Csv => {
+            writeln!(w, "did,handle,display_name")?;
+            while let Some(m) = members.next().await {
+                let m = m?;
+                writeln!(
+                    w,
+                    "{},{},{}",
+                    m.did.as_str(),
+                    m.handle.as_str(),
+                    csv_field(m.display_name.as_deref().unwrap_or_default())
+                )?;
+            }
+        }
+        Format::Json => {
+            let members: Vec<_> = members.try_collect().await?;
+            serde_json::to_writer_pretty(&mut w, &members)?;
+            writeln!(w)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads `input` from line `start`, queuing its accounts a batch at a time.
+/// Calls `checkpoint` with the number of lines read once each batch is written.
+async fn read_input<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+    agent: &BskyAgent<T, S>,
+    input: PathBuf,
+    start: usize,
+    seen: &mut HashSet<Did>,
+    exemptions: &Exemptions,
+    writer: &Writer,
+    mut checkpoint: impl FnMut(usize) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut dids = Vec::new();
+    let lines = BufReader::new(File::open(input)?).lines().enumerate();
+    for (i, line) in lines.skip(start) {
+        let line = line?;
+        let line = line.trim();
+        if !(line.is_empty() || line.starts_with('#')) {
+            match resolver::resolve_actor(agent, line).await {
+                Ok(did) if exemptions.is_exempt(&did) => {
+                    warn!(msg = "exempted, skipping", entry = line)
+                }
+                Ok(did) if !seen.insert(did.clone()) => {
+                    warn!(msg = "already in list, skipping", entry = line)
+                }
+                Ok(did) => dids.push(did),
+                Err(e) => warn!(msg = "could not resolve, skipping", entry = line, err = %e),
+            }
+        }
+        if dids.len() == MAX_WRITES {
+            writer
+                .add_batch(Priority::Backfill, std::mem::take(&mut dids))
+                .await?;
+            writer.flush(Priority::Backfill).await?;
+            checkpoint(i + 1)?;
+        }
+    }
+    if !dids.is_empty() {
+        writer.add_batch(Priority::Backfill, dids).await?;
+    }
+    writer.flush(Priority::Backfill).await
+}
+
+async fn import<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+    agent: &BskyAgent<T, S>,
+    list: List,
+    input: PathBuf,
+    cursor: PathBuf,
+    exemptions: &Exemptions,
+) -> Result<(), Box<dyn Error>> {
+    let mut seen: HashSet<Did> = List::get_members(list.uri().to_string(), agent, None)
+        .await
+        .map_ok(|m| m.did)
+        .try_collect()
+        .await?;
+    info!(msg = "got existing members", nb = seen.len());
+
+    // progress is kept under the list owner, once per list and input
+    let owner = agent.get_session().await.ok_or("not logged in")?.data.did;
+    let states =
+        StateStore::load(&cursor)?.with_scope(format!("import {} {}", list.uri(), input.display()));
+    let start = states
+        .get_or_insert(&owner, list.clone())
+        .cursor()
+        .and_then(|c| c.parse().ok())
+        .unwrap_or(0);
+    info!(msg = "importing", start_line = start);
+
+    // writes are retried on transient errors, the import stops on other ones
+    let (writer, mut queue) = writer::channel(WRITE_QUEUE_SIZE);
+    let read = read_input(
+        agent,
+        input,
+        start,
+        &mut seen,
+        exemptions,
+        &writer,
+        |lines| {
+            info!(msg = "imported", lines = lines);
+            states.set_cursor(&owner, lines.to_string());
+            states.save()
+        },
+    );
+    select! {
+        res = read => res?,
+        res = queue.run(agent, &list) => {
+            res?;
+            return Err("write queue stopped".into());
+        }
+    }
+    writer.metrics().log();
+
+    states.clear_cursor(&owner);
+    states.save()
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    // logs go to stderr, stdout is for exports
+    tracing_subscriber::fmt().with_writer(io::stderr).init();
+    let Args { config, command } = Args::parse();
+    let agent = session::load_agent(RateLimited::default(), &config, None).await?;
+
+    // lists can be given as bsky.app links or AT-URIs
+    match command {
+        Command::Create {
+            name,
+            description,
+            purpose,
+        } => {
+            let list = List::create(&agent, name, description, purpose).await?;
+            println!("{}", list.uri());
+        }
+        Command::Show { list } => {
+            let list = resolver::resolve_list(&agent, &list).await?;
+            let view = List::get_view(list.clone(), &agent).await?;
+            let nb = List::get_nb_members(list, &agent).await;
+            println!("uri:         {}", view.uri);
+            println!("name:        {}", view.name);
+            println!("purpose:     {}", view.purpose);
+            println!(
+                "description: {}",
+                view.description.as_deref().unwrap_or_default()
+            );
+            println!("members:     {}", nb.unwrap_or_default());
+        }
+        Command::Export {
+            list,
+            format,
+            output,
+        } => {
+            let list = resolver::resolve_list(&agent, &list).await?;
+            export(&agent, list, format, output).await?
+        }
+        Command::Import {
+            list,
+            input,
+            cursor,
+            exempt,
+        } => {
+            let purposes = [Purpose::Mod, Purpose::Curate, Purpose::Reference];
+            let list = List::open_as(&agent, &list, &purposes).await?;
+            let exemptions = Exemptions::load(&agent, exempt.config(&agent).await?).await?;
+            import(&agent, list.into_list(), input, cursor, &exemptions).await?
+        }
+    }
+    Ok(())
+}
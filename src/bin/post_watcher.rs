@@ -0,0 +1,319 @@
+use atrium_api::agent::store::SessionStore;
+use atrium_api::types::string::{Did, Language};
+use atrium_api::xrpc::XrpcClient;
+use bsky_sdk::BskyAgent;
+use clap::Parser;
+use feed2block::dedup::SeenSet;
+use feed2block::exempt::{ExemptArgs, Exemptions};
+use feed2block::followers::{then_error, until_error};
+use feed2block::profile_filter::{ProfileFilter, ProfileFilterArgs};
+use feed2block::search::{from_search, Search};
+use feed2block::session::{self, relogin, ReloginArgs};
+use feed2block::state::StateStore;
+use feed2block::subwatch::{PostMatcher, PostWatcher, RECONNECT_DELAY};
+use feed2block::writer::{self, Priority, Writer};
+use feed2block::{
+    list::{List, Purpose},
+    ratelimit::RateLimited,
+};
+use futures_util::{pin_mut, StreamExt, TryStreamExt};
+use std::sync::{Arc, Mutex};
+use std::{error::Error, path::PathBuf};
+use tokio::{join, task, time};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+const JETSTREAM_URL: &str = "wss://jetstream2.us-east.bsky.network/";
+
+/// Dids waiting to be written, per priority class.
+const WRITE_QUEUE_SIZE: usize = 1000;
+
+/// Adds authors of posts matching any of the patterns to a list.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// list to add authors to: mod or curate list, AT-URI or bsky.app link
+    #[arg(short, long, env, visible_alias = "modlist", short_alias = 'm')]
+    list: String,
+
+    /// regex matched against post text, e.g. (?i)\bfoo\b
+    #[arg(long)]
+    text: Vec<String>,
+
+    /// regex matched against hashtags, without the #
+    #[arg(long)]
+    tag: Vec<String>,
+
+    /// regex matched against links
+    #[arg(long)]
+    link: Vec<String>,
+
+    /// also match --text patterns against image and video alt texts
+    #[arg(long, default_value = "false")]
+    alt_text: bool,
+
+    /// backfills authors of posts found with this search query, while watching new posts
+    #[arg(long)]
+    search: Option<String>,
+
+    /// only backfill posts from that date (2024-01-01) or datetime
+    #[arg(long, requires = "search")]
+    since: Option<String>,
+
+    /// only backfill posts before that date (2024-01-01) or datetime
+    #[arg(long, requires = "search")]
+    until: Option<String>,
+
+    /// only backfill posts in that language
+    #[arg(long, requires = "search")]
+    lang: Option<String>,
+
+    #[arg(short, long, default_value = "config.json")]
+    config: PathBuf,
+
+    /// where the search backfill cursor is kept, to resume it
+    #[arg(long, default_value = "search.json")]
+    cursor: PathBuf,
+
+    #[command(flatten)]
+    relogin: ReloginArgs,
+
+    #[command(flatten)]
+    exempt: ExemptArgs,
+
+    #[command(flatten)]
+    profile: ProfileFilterArgs,
+}
+
+/// Writes the authors of posts found by `search`, saving the cursor of each page once written.
+#[allow(clippy::too_many_arguments)]
+async fn run_search<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+    agent: &BskyAgent<T, S>,
+    search: Search,
+    owner: &Did,
+    states: &StateStore,
+    modlist: &List,
+    seen: &SeenSet,
+    profiles: &ProfileFilter,
+    writer: &Writer,
+) -> Result<(), Box<dyn Error>> {
+    let cursor = states
+        .get_or_insert(owner, modlist.clone())
+        .cursor()
+        .map(String::from);
+    info!(msg = "backfilling search", start_cursor = cursor);
+
+    let posts = from_search(agent, search, cursor)
+        .await
+        .map_ok(|(post, cursor)| {
+            info!(msg = "post found", did = ?post.author.did, uri = post.uri);
+            (post.data.author.data.did, cursor)
+        });
+
+    // stops at the first page or profiles that can't be read, returning the error once the
+    // pages before are written
+    let error = Mutex::new(None);
+    let posts = until_error(posts, &error);
+    let posts = then_error(
+        seen.mark(profiles.filter(agent, seen.filter(posts))),
+        &error,
+    );
+    writer
+        .add_stream_checkpointed(Priority::Backfill, posts, |c| {
+            info!(msg = "search written up to cursor", cursor = c);
+            states.set_cursor(owner, c.to_string());
+            states.save()
+        })
+        .await?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    tracing_subscriber::fmt::init();
+    let Args {
+        list: modlist,
+        text,
+        tag,
+        link,
+        alt_text,
+        search,
+        since,
+        until,
+        lang,
+        config,
+        cursor,
+        relogin: relogin_args,
+        exempt,
+        profile,
+    } = Args::parse();
+
+    let live = !(text.is_empty() && tag.is_empty() && link.is_empty());
+    if !live && search.is_none() {
+        return Err(
+            "nothing to match: give at least one of --text, --tag, --link or --search".into(),
+        );
+    }
+    let lang = match lang {
+        Some(l) => Some(
+            l.parse::<Language>()
+                .map_err(|_| format!("invalid language: {l}"))?,
+        ),
+        None => None,
+    };
+    let search = search.map(|query| Search {
+        query,
+        since,
+        until,
+        lang,
+    });
+    let scope = search.as_ref().map(|s| {
+        format!(
+            "search {} {:?} {:?} {:?}",
+            s.query,
+            s.since,
+            s.until,
+            s.lang.as_ref().map(|l| l.as_ref().to_string())
+        )
+    });
+    let matcher = PostMatcher::new(&text, &tag, &link)?;
+    let matcher = match alt_text {
+        true => matcher.with_alt_text(),
+        false => matcher,
+    };
+    let profiles = ProfileFilter::from(profile);
+
+    let token = CancellationToken::new();
+
+    let credentials = relogin_args.credentials(&config).await?;
+    let client = RateLimited::default();
+    let agent = session::load_agent(client, &config, credentials.as_ref()).await?;
+
+    // checks that the modlist exists and is ours before writing to it
+    let modlist = List::open_as(&agent, &modlist, &[Purpose::Mod, Purpose::Curate])
+        .await?
+        .into_list();
+
+    // searches are kept under the list owner, one per list and query
+    let owner = agent.get_session().await.ok_or("not logged in")?.data.did;
+    let states = StateStore::load(&cursor)?;
+    let states = match scope {
+        Some(scope) => states.with_scope(format!("{} {scope}", modlist.uri())),
+        None => states,
+    };
+
+    let exemptions = Arc::new(Exemptions::load(&agent, exempt.config(&agent).await?).await?);
+
+    // skip current members, whether added by a previous run or by hand
+    let seen = SeenSet::from_list(modlist.uri().to_string(), &agent).await?;
+
+    let cloned_token = token.clone();
+
+    let (writer, queue) = writer::channel(WRITE_QUEUE_SIZE);
+    let mut queue = queue
+        .with_exemptions(exemptions, exempt.refresh_period())
+        .with_seen(seen.clone());
+
+    let jetstream = task::spawn(async move {
+        let watch = async {
+            if !live {
+                return Ok(());
+            }
+            loop {
+                let watcher =
+                    match PostWatcher::new(JETSTREAM_URL.parse().unwrap(), matcher.clone()).await {
+                        Ok(w) => w,
+                        Err(e) => {
+                            warn!(msg = "could not connect to post stream, retrying", err = %e);
+                            time::sleep(RECONNECT_DELAY).await;
+                            continue;
+                        }
+                    };
+                info!(msg = "connected to post stream", url = JETSTREAM_URL);
+
+                let posts = watcher.stream().await.map(|post| {
+                    info!(
+                        msg = "post matched",
+                        did = ?post.author,
+                        uri = post.uri,
+                        field = %post.field,
+                        pattern = post.pattern
+                    );
+                    (post.author, post.uri)
+                });
+                let error = Mutex::new(None);
+                let posts = until_error(
+                    seen.mark(profiles.filter(&agent, seen.filter(posts))),
+                    &error,
+                );
+                pin_mut!(posts);
+                while let Some((did, uri)) = posts.next().await {
+                    info!(msg = "adding author", did = ?did, uri = uri);
+                    if let Err(e) = writer.add(Priority::Live, did).await {
+                        return Err(e.to_string());
+                    }
+                }
+                let error = error.lock().unwrap().take();
+                match error {
+                    Some(e) => warn!(msg = "could not get profiles, reconnecting", err = %e),
+                    None => warn!(msg = "post stream ended"),
+                }
+            }
+        };
+
+        let backfill = async {
+            let Some(search) = search else {
+                return Ok(());
+            };
+            loop {
+                // re-read state on retries to resume from the last checkpoint
+                let res = run_search(
+                    &agent,
+                    search.clone(),
+                    &owner,
+                    &states,
+                    &modlist,
+                    &seen,
+                    &profiles,
+                    &writer,
+                )
+                .await
+                .map_err(|e| {
+                    warn!(msg = "search backfill failed", err = %e);
+                    session::is_auth_error(e.as_ref())
+                });
+                match res {
+                    Err(true) if relogin(&agent, credentials.as_ref()).await => continue,
+                    Err(_) => return Err("search backfill failed".to_string()),
+                    Ok(()) => break,
+                }
+            }
+            info!(msg = "search backfill done", nb_seen = seen.len());
+            // posts come newest first: the next backfill starts over to get the new ones
+            states.clear_cursor(&owner);
+            if let Err(e) = states.save() {
+                warn!(msg = "could not write state", err = %e);
+            }
+            Ok(())
+        };
+
+        // without --live, done once the search is
+        let sources = async {
+            let (watch, backfill) = join!(watch, backfill);
+            watch.and(backfill)
+        };
+        queue
+            .serve(
+                &agent,
+                &modlist,
+                credentials.as_ref(),
+                &cloned_token,
+                sources,
+            )
+            .await
+    });
+
+    let res = writer::until_ctrl_c(jetstream, &token).await;
+    info!(msg = "shutting down!");
+    res
+}
@@ -0,0 +1,206 @@
+use async_stream::stream;
+use clap::Parser;
+use feed2block::dedup::SeenSet;
+use feed2block::exempt::{ExemptArgs, Exemptions};
+use feed2block::followers::until_error;
+use feed2block::pileon::{Burst, Rates, Repeated};
+use feed2block::profile_filter::{Predicate, ProfileFilter, ProfileFilterArgs};
+use feed2block::session::{self, ReloginArgs};
+use feed2block::subwatch::{InteractionWatcher, RECONNECT_DELAY};
+use feed2block::writer::{self, Priority};
+use feed2block::{
+    list::{List, Purpose},
+    ratelimit::RateLimited,
+    resolver,
+};
+use futures_util::{pin_mut, StreamExt};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{error::Error, path::PathBuf};
+use tokio::{task, time};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+const JETSTREAM_URL: &str = "wss://jetstream2.us-east.bsky.network/";
+
+/// Dids waiting to be written, per priority class.
+const WRITE_QUEUE_SIZE: usize = 1000;
+
+/// Adds accounts piling on replies to, or mentions of, an account to a list.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// list to add authors to: mod or curate list, AT-URI or bsky.app link
+    #[arg(short, long, env, visible_alias = "modlist", short_alias = 'm')]
+    list: String,
+
+    /// account to protect: handle, did or profile link, ours by default
+    #[arg(short, long)]
+    protect: Option<String>,
+
+    /// adds accounts replying or mentioning more than this many times within --window
+    #[arg(long)]
+    max_replies: Option<usize>,
+
+    /// in seconds
+    #[arg(long, default_value = "3600")]
+    window: u64,
+
+    /// adds new accounts once more than this many of them replied or mentioned within --burst-window
+    #[arg(long)]
+    burst: Option<usize>,
+
+    /// in seconds
+    #[arg(long, default_value = "600")]
+    burst_window: u64,
+
+    /// profile rule telling new accounts, see --only-if
+    #[arg(long, default_value = "age<30d")]
+    new_if: Vec<Predicate>,
+
+    #[arg(short, long, default_value = "config.json")]
+    config: PathBuf,
+
+    #[command(flatten)]
+    relogin: ReloginArgs,
+
+    #[command(flatten)]
+    exempt: ExemptArgs,
+
+    #[command(flatten)]
+    profile: ProfileFilterArgs,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    tracing_subscriber::fmt::init();
+    let Args {
+        list: modlist,
+        protect,
+        max_replies,
+        window,
+        burst,
+        burst_window,
+        new_if,
+        config,
+        relogin: relogin_args,
+        exempt,
+        profile,
+    } = Args::parse();
+
+    if max_replies.is_none() && burst.is_none() {
+        return Err("no rule: give at least one of --max-replies or --burst".into());
+    }
+    let rates = Rates::new(
+        max_replies.map(|max| Repeated {
+            max,
+            window: Duration::from_secs(window),
+        }),
+        burst.map(|max| Burst {
+            max,
+            window: Duration::from_secs(burst_window),
+        }),
+    );
+    // only used to hydrate and match profiles of authors
+    let new_accounts = ProfileFilter::new(new_if, vec![]);
+    let profiles = ProfileFilter::from(profile);
+
+    let token = CancellationToken::new();
+
+    let credentials = relogin_args.credentials(&config).await?;
+    let client = RateLimited::default();
+    let agent = session::load_agent(client, &config, credentials.as_ref()).await?;
+
+    // checks that the modlist exists and is ours before writing to it
+    let modlist = List::open_as(&agent, &modlist, &[Purpose::Mod, Purpose::Curate])
+        .await?
+        .into_list();
+
+    let exemptions = Arc::new(Exemptions::load(&agent, exempt.config(&agent).await?).await?);
+
+    let protected = match protect {
+        Some(p) => resolver::resolve_actor(&agent, &p).await?,
+        None => agent.get_session().await.ok_or("not logged in")?.data.did,
+    };
+    info!(msg = "protecting", did = ?protected);
+
+    // skip current members, whether added by a previous run or by hand
+    let seen = SeenSet::from_list(modlist.uri().to_string(), &agent).await?;
+
+    let cloned_token = token.clone();
+
+    let (writer, queue) = writer::channel(WRITE_QUEUE_SIZE);
+    let mut queue = queue
+        .with_exemptions(exemptions, exempt.refresh_period())
+        .with_seen(seen.clone());
+
+    let jetstream = task::spawn(async move {
+        let watch = async {
+            // the interaction stream below is rebuilt on reconnection, it only borrows those
+            let (agent, new_accounts, mut rates) = (&agent, &new_accounts, rates);
+            loop {
+                let rates = &mut rates;
+                let watcher = match InteractionWatcher::new(
+                    JETSTREAM_URL.parse().unwrap(),
+                    protected.clone(),
+                )
+                .await
+                {
+                    Ok(w) => w,
+                    Err(e) => {
+                        warn!(msg = "could not connect to post stream, retrying", err = %e);
+                        time::sleep(RECONNECT_DELAY).await;
+                        continue;
+                    }
+                };
+                info!(msg = "connected to post stream", url = JETSTREAM_URL);
+
+                let interactions = watcher.stream().await;
+                let triggered = stream! {
+                    pin_mut!(interactions);
+                    while let Some(i) = interactions.next().await {
+                        info!(msg = "interaction", kind = %i.kind, did = ?i.author, uri = i.uri);
+                        let new_account = match rates.needs_age() {
+                            true => new_accounts
+                                .hydrate(agent, std::slice::from_ref(&i.author))
+                                .await
+                                .map_err(|e| warn!(msg = "could not get profile", did = ?i.author, err = %e))
+                                .ok()
+                                .and_then(|p| p.into_iter().next().flatten())
+                                .is_some_and(|p| new_accounts.matches(&p)),
+                            false => false,
+                        };
+                        for (did, trigger) in rates.record(&i.author, i.ts, new_account) {
+                            yield (did, (trigger, i.uri.clone()));
+                        }
+                    }
+                };
+                let error = Mutex::new(None);
+                let triggered = until_error(
+                    seen.mark(profiles.filter(agent, seen.filter(triggered))),
+                    &error,
+                );
+                pin_mut!(triggered);
+                while let Some((did, (trigger, uri))) = triggered.next().await {
+                    info!(msg = "adding", did = ?did, trigger = %trigger, uri = uri);
+                    if let Err(e) = writer.add(Priority::Live, did).await {
+                        return Err(e.to_string());
+                    }
+                }
+                let error = error.lock().unwrap().take();
+                match error {
+                    Some(e) => warn!(msg = "could not get profiles, reconnecting", err = %e),
+                    None => warn!(msg = "post stream ended"),
+                }
+            }
+        };
+
+        queue
+            .serve(&agent, &modlist, credentials.as_ref(), &cloned_token, watch)
+            .await
+    });
+
+    let res = writer::until_ctrl_c(jetstream, &token).await;
+    info!(msg = "shutting down!");
+    res
+}
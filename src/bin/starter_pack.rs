@@ -0,0 +1,117 @@
+use clap::Parser;
+use feed2block::dedup::SeenSet;
+use feed2block::exempt::{ExemptArgs, Exemptions};
+use feed2block::followers::{then_error, until_error};
+use feed2block::session::{self, ReloginArgs};
+use feed2block::starter_pack::{from_joined, from_members, get_view};
+use feed2block::state::StateStore;
+use feed2block::{
+    list::{List, Purpose},
+    ratelimit::RateLimited,
+    resolver,
+};
+use futures_util::StreamExt;
+use std::{error::Error, path::PathBuf, sync::Mutex};
+use tracing::info;
+
+/// Adds the members of a starter pack, and optionally everyone who joined through it, to a list.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// starter pack: AT-URI or bsky.app/starter-pack link
+    starter_pack: String,
+
+    /// list to add accounts to: mod or curate list, AT-URI or bsky.app link
+    #[arg(short, long, env, visible_alias = "modlist", short_alias = 'm')]
+    list: String,
+
+    /// also add accounts who joined bluesky through the pack, found among its creator's followers
+    #[arg(long, default_value = "false")]
+    joined: bool,
+
+    #[arg(short, long, default_value = "config.json")]
+    config: PathBuf,
+
+    /// where the cursor of the joiners search is kept, to resume it
+    #[arg(long, default_value = "joiners.json")]
+    cursor: PathBuf,
+
+    #[command(flatten)]
+    relogin: ReloginArgs,
+
+    #[command(flatten)]
+    exempt: ExemptArgs,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    tracing_subscriber::fmt::init();
+    let Args {
+        starter_pack,
+        list: modlist,
+        joined,
+        config,
+        cursor,
+        relogin,
+        exempt,
+    } = Args::parse();
+
+    let credentials = relogin.credentials(&config).await?;
+    let agent = session::load_agent(RateLimited::default(), &config, credentials.as_ref()).await?;
+
+    // checks that the modlist exists and is ours before writing to it
+    let modlist = List::open_as(&agent, &modlist, &[Purpose::Mod, Purpose::Curate])
+        .await?
+        .into_list();
+
+    // the owner, its friends and allowlisted accounts never get added
+    let exemptions = Exemptions::load(&agent, exempt.config(&agent).await?).await?;
+
+    let starter_pack = resolver::resolve_starter_pack(&agent, &starter_pack).await?;
+    let view = get_view(&agent, starter_pack).await?;
+    info!(
+        msg = "got starter pack",
+        uri = view.uri,
+        creator = ?view.creator.did,
+        joined = view.joined_all_time_count
+    );
+
+    // skip current members, whether added by a previous run or by hand
+    let seen = SeenSet::from_list(modlist.uri().to_string(), &agent).await?;
+
+    let error = Mutex::new(None);
+    let members = until_error(from_members(&agent, &view).await?, &error);
+    let members = seen.mark(seen.filter(exemptions.filter(members)).map(Ok));
+    modlist
+        .add_stream(&agent, then_error(members, &error))
+        .await?;
+    info!(msg = "starter pack members added", nb_seen = seen.len());
+
+    if joined {
+        // joiners are looked for among the creator's followers, once per pack and list
+        let creator = view.creator.did.clone();
+        let states =
+            StateStore::load(&cursor)?.with_scope(format!("joined {} {}", view.uri, modlist.uri()));
+        let cursor = states
+            .get_or_insert(&creator, modlist.clone())
+            .cursor()
+            .map(String::from);
+        info!(msg = "looking for joiners", start_cursor = cursor);
+
+        let error = Mutex::new(None);
+        let joiners = until_error(from_joined(&agent, &view, cursor).await, &error);
+        let joiners = seen.mark(seen.filter(exemptions.filter(joiners)).map(Ok));
+        modlist
+            .add_stream_checkpointed(&agent, then_error(joiners, &error), |c| {
+                info!(msg = "joiners written up to cursor", cursor = c);
+                states.set_cursor(&creator, c.to_string());
+                states.save()
+            })
+            .await?;
+        info!(msg = "joiners added", nb_seen = seen.len());
+        // followers come newest first: the next run starts over to get the new ones
+        states.clear_cursor(&creator);
+        states.save()?;
+    }
+    Ok(())
+}
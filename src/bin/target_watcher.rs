@@ -0,0 +1,288 @@
+use async_stream::stream;
+use clap::{Parser, ValueEnum};
+use feed2block::dedup::SeenSet;
+use feed2block::exempt::{ExemptArgs, Exemptions};
+use feed2block::followers::until_error;
+use feed2block::profile_filter::{ProfileFilter, ProfileFilterArgs};
+use feed2block::session::{self, ReloginArgs};
+use feed2block::subwatch::{TargetWatcher, Targeting, RECONNECT_DELAY};
+use feed2block::writer::{self, Priority};
+use feed2block::{
+    list::{List, Purpose},
+    ratelimit::RateLimited,
+    resolver,
+};
+use futures_util::{future, pin_mut, StreamExt};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::{error::Error, path::PathBuf};
+use tokio::{select, task, time};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+const JETSTREAM_URL: &str = "wss://jetstream2.us-east.bsky.network/";
+
+/// Dids waiting to be written, per priority class.
+const WRITE_QUEUE_SIZE: usize = 1000;
+
+/// Number of list purposes kept, above which they're all dropped.
+const PURPOSES_SIZE: usize = 10_000;
+
+/// What to do with an account blocking or listing a protected one.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Action {
+    /// add it to the --list
+    Add,
+    /// add it to the --tag-list
+    Tag,
+    /// only log it
+    Log,
+}
+
+/// Adds accounts blocking protected accounts, or putting them on lists, to a list.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// list to add accounts to: mod or curate list, AT-URI or bsky.app link
+    #[arg(short, long, env, visible_alias = "modlist", short_alias = 'm')]
+    list: String,
+
+    /// account to protect: handle, did or profile link, ours by default
+    #[arg(short, long)]
+    protect: Vec<String>,
+
+    /// list for tagged accounts, mod or curate: AT-URI or bsky.app link
+    #[arg(long)]
+    tag_list: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = Action::Add)]
+    on_block: Action,
+
+    /// when listed on a modlist
+    #[arg(long, value_enum, default_value_t = Action::Add)]
+    on_modlist: Action,
+
+    /// when listed on a curate list
+    #[arg(long, value_enum, default_value_t = Action::Log)]
+    on_curatelist: Action,
+
+    /// when listed on a reference list, e.g. a starter pack's, or a list that can't be read
+    #[arg(long, value_enum, default_value_t = Action::Log)]
+    on_other_list: Action,
+
+    #[arg(short, long, default_value = "config.json")]
+    config: PathBuf,
+
+    #[command(flatten)]
+    relogin: ReloginArgs,
+
+    #[command(flatten)]
+    exempt: ExemptArgs,
+
+    #[command(flatten)]
+    profile: ProfileFilterArgs,
+}
+
+/// Writes queued dids into `list`, logging in again when the session expires.
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    tracing_subscriber::fmt::init();
+    let Args {
+        list: modlist,
+        protect,
+        tag_list,
+        on_block,
+        on_modlist,
+        on_curatelist,
+        on_other_list,
+        config,
+        relogin: relogin_args,
+        exempt,
+        profile,
+    } = Args::parse();
+
+    let actions = [on_block, on_modlist, on_curatelist, on_other_list];
+    if tag_list.is_none() && actions.contains(&Action::Tag) {
+        return Err("tagging needs a --tag-list".into());
+    }
+    let profiles = ProfileFilter::from(profile);
+
+    let token = CancellationToken::new();
+
+    let credentials = relogin_args.credentials(&config).await?;
+    let client = RateLimited::default();
+    let agent = session::load_agent(client, &config, credentials.as_ref()).await?;
+
+    // checks that the lists exist and are ours before writing to them
+    let modlist = List::open_as(&agent, &modlist, &[Purpose::Mod, Purpose::Curate])
+        .await?
+        .into_list();
+    let tag_list = match tag_list {
+        Some(l) => Some(
+            List::open_as(&agent, &l, &[Purpose::Mod, Purpose::Curate])
+                .await?
+                .into_list(),
+        ),
+        None => None,
+    };
+
+    let exemptions = Arc::new(Exemptions::load(&agent, exempt.config(&agent).await?).await?);
+
+    let mut protected = vec![];
+    for p in &protect {
+        protected.push(resolver::resolve_actor(&agent, p).await?);
+    }
+    if protected.is_empty() {
+        protected.push(agent.get_session().await.ok_or("not logged in")?.data.did);
+    }
+    info!(msg = "protecting", dids = ?protected);
+
+    // skip current members, whether added by a previous run or by hand
+    let seen = SeenSet::from_list(modlist.uri().to_string(), &agent).await?;
+    let tag_seen = match &tag_list {
+        Some(l) => SeenSet::from_list(l.uri().to_string(), &agent).await?,
+        None => SeenSet::default(),
+    };
+
+    let cloned_token = token.clone();
+
+    let (writer, queue) = writer::channel(WRITE_QUEUE_SIZE);
+    let mut queue = queue
+        .with_exemptions(exemptions.clone(), exempt.refresh_period())
+        .with_seen(seen.clone());
+    let (tag_writer, tag_queue) = writer::channel(WRITE_QUEUE_SIZE);
+    let mut tag_queue = tag_queue
+        .with_exemptions(exemptions.clone(), exempt.refresh_period())
+        .with_seen(tag_seen.clone());
+
+    let jetstream = task::spawn(async move {
+        let watch = async {
+            // the event stream below is rebuilt on reconnection, it only borrows the agent
+            let agent = &agent;
+            // purpose of the lists we've been put on, they can be anyone's: None for unknown ones
+            let mut purposes: HashMap<String, Option<Purpose>> = HashMap::new();
+            loop {
+                let watcher =
+                    match TargetWatcher::new(JETSTREAM_URL.parse().unwrap(), protected.clone())
+                        .await
+                    {
+                        Ok(w) => w,
+                        Err(e) => {
+                            warn!(msg = "could not connect to event stream, retrying", err = %e);
+                            time::sleep(RECONNECT_DELAY).await;
+                            continue;
+                        }
+                    };
+                info!(msg = "connected to event stream", url = JETSTREAM_URL);
+
+                let events = watcher.stream().await;
+                let purposes = &mut purposes;
+                let targeting = stream! {
+                    pin_mut!(events);
+                    while let Some(e) = events.next().await {
+                        let action = match &e.targeting {
+                            Targeting::Block => on_block,
+                            Targeting::ListItem { list } => {
+                                let purpose = match purposes.get(list) {
+                                    Some(purpose) => *purpose,
+                                    // lists that can't be read are asked again next time
+                                    None => match List::get_view(list.clone(), agent).await {
+                                        Ok(view) => {
+                                            let purpose = Purpose::parse(&view.purpose);
+                                            if purposes.len() >= PURPOSES_SIZE {
+                                                purposes.clear();
+                                            }
+                                            purposes.insert(list.clone(), purpose);
+                                            purpose
+                                        }
+                                        Err(e) => {
+                                            warn!(msg = "could not get list", list = list, err = %e);
+                                            None
+                                        }
+                                    },
+                                };
+                                match purpose {
+                                    Some(Purpose::Mod) => on_modlist,
+                                    Some(Purpose::Curate) => on_curatelist,
+                                    // reference lists, or any future purpose
+                                    _ => on_other_list,
+                                }
+                            }
+                        };
+                        info!(
+                            msg = "targeted",
+                            did = ?e.author,
+                            subject = ?e.subject,
+                            targeting = %e.targeting,
+                            action = ?action
+                        );
+                        if action != Action::Log {
+                            yield (e.author, action);
+                        }
+                    }
+                };
+                let error = Mutex::new(None);
+                let targeting = until_error(profiles.filter(agent, targeting), &error);
+                pin_mut!(targeting);
+                while let Some((did, action)) = targeting.next().await {
+                    let res = match action {
+                        Action::Add if seen.insert(&did) => {
+                            info!(msg = "adding", did = ?did);
+                            writer.add(Priority::Live, did).await
+                        }
+                        Action::Tag if tag_seen.insert(&did) => {
+                            info!(msg = "tagging", did = ?did);
+                            tag_writer.add(Priority::Live, did).await
+                        }
+                        _ => Ok(()),
+                    };
+                    if let Err(e) = res {
+                        return Err(e.to_string());
+                    }
+                }
+                let error = error.lock().unwrap().take();
+                match error {
+                    Some(e) => warn!(msg = "could not get profiles, reconnecting", err = %e),
+                    None => warn!(msg = "event stream ended"),
+                }
+            }
+        };
+
+        // the tag list is written alongside, either writer stopping stops everything
+        let tag = async {
+            let res = match &tag_list {
+                Some(l) => tag_queue.run_relogin(&agent, l, credentials.as_ref()).await,
+                None => future::pending().await,
+            };
+            warn!(msg = "tag writer stopped");
+            tag_writer.metrics().log();
+            Err(match res {
+                Ok(()) => "tag writer stopped".to_string(),
+                Err(e) => format!("tag writer stopped: {e}"),
+            })
+        };
+        let sources = async {
+            select! {
+                res = watch => res,
+                res = tag => res,
+            }
+        };
+        let res = queue
+            .serve(
+                &agent,
+                &modlist,
+                credentials.as_ref(),
+                &cloned_token,
+                sources,
+            )
+            .await;
+        if cloned_token.is_cancelled() {
+            tag_writer.metrics().log();
+        }
+        res
+    });
+
+    let res = writer::until_ctrl_c(jetstream, &token).await;
+    info!(msg = "shutting down!");
+    res
+}
@@ -0,0 +1,151 @@
+//! Dedup of dids written to a list by concurrent sources
+//!
+//! Backfill and live events can both yield the same follower: each did goes
+//! through a shared [`SeenSet`] so that it's only written once. Loading it with
+//! the list members also skips those already added, by a previous run or by hand.
+//!
+//! Dids are marked seen as they're queued, once every other filter kept them: the
+//! [`WriteQueue`](crate::writer::WriteQueue) forgets those whose write fails, so that
+//! they're written again when seen again.
+
+use std::{
+    collections::HashSet,
+    error::Error,
+    future,
+    sync::{Arc, Mutex},
+};
+
+use atrium_api::{agent::store::SessionStore, types::string::Did, xrpc::XrpcClient};
+use bsky_sdk::BskyAgent;
+use futures_core::Stream;
+use futures_util::{StreamExt, TryStreamExt};
+use tracing::info;
+
+use crate::list::List;
+
+/// Clones share the same set.
+#[derive(Debug, Default, Clone)]
+pub struct SeenSet(Arc<Mutex<HashSet<Did>>>);
+
+impl SeenSet {
+    /// Loads the current members of a list.
+    pub async fn from_list<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        list: String,
+        agent: &BskyAgent<T, S>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let members: HashSet<Did> = List::get_members(list, agent, None)
+            .await
+            .map_ok(|m| m.did)
+            .try_collect()
+            .await?;
+        info!(msg = "loaded list members", nb = members.len());
+        Ok(Self(Arc::new(Mutex::new(members))))
+    }
+
+    /// Starts from dids written elsewhere, e.g. accounts already labeled.
+    pub fn from_dids(dids: impl IntoIterator<Item = Did>) -> Self {
+        Self(Arc::new(Mutex::new(dids.into_iter().collect())))
+    }
+
+    /// Dids seen by all of `sets`, e.g. those written to every output.
+    pub fn intersection(sets: &[SeenSet]) -> Self {
+        let Some((first, rest)) = sets.split_first() else {
+            return Self::default();
+        };
+        let first: Vec<Did> = first.0.lock().unwrap().iter().cloned().collect();
+        Self::from_dids(
+            first
+                .into_iter()
+                .filter(|did| rest.iter().all(|set| set.contains(did))),
+        )
+    }
+
+    /// Marks a did as seen, returns false if it already was.
+    pub fn insert(&self, did: &Did) -> bool {
+        self.0.lock().unwrap().insert(did.clone())
+    }
+
+    /// Forgets a did, so that it can be written again.
+    pub fn remove(&self, did: &Did) -> bool {
+        self.0.lock().unwrap().remove(did)
+    }
+
+    pub fn contains(&self, did: &Did) -> bool {
+        self.0.lock().unwrap().contains(did)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drops already seen dids from a stream of (did, cursor), without marking the others:
+    /// see [`SeenSet::mark`].
+    pub fn filter<'a, C: 'a>(
+        &'a self,
+        dids: impl Stream<Item = (Did, C)> + 'a,
+    ) -> impl Stream<Item = (Did, C)> + 'a {
+        dids.filter(move |(did, _)| future::ready(!self.contains(did)))
+    }
+
+    /// Marks dids as seen as they're read, dropping those already seen.
+    ///
+    /// Goes right before queuing, so that dids dropped by other filters aren't marked.
+    pub fn mark<'a, C: 'a, E: 'a>(
+        &'a self,
+        dids: impl Stream<Item = Result<(Did, C), E>> + 'a,
+    ) -> impl Stream<Item = Result<(Did, C), E>> + 'a {
+        dids.filter(move |item| {
+            future::ready(match item {
+                Ok((did, _)) => self.insert(did),
+                Err(_) => true,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use atrium_api::types::string::Did;
+    use futures_util::{stream, StreamExt};
+
+    use super::SeenSet;
+
+    #[tokio::test]
+    async fn test_filter() {
+        let a: Did = "did:plc:aaaaaaaaaaaaaaaaaaaaaaaa".parse().unwrap();
+        let b: Did = "did:plc:bbbbbbbbbbbbbbbbbbbbbbbb".parse().unwrap();
+        let seen = SeenSet::default();
+        seen.insert(&a);
+
+        let dids = stream::iter(vec![
+            (a.clone(), None),
+            (b.clone(), None),
+            (b.clone(), None),
+        ]);
+        let out: Vec<(Did, Option<String>)> = seen.filter(dids).collect().await;
+        assert_eq!(out, vec![(b.clone(), None), (b.clone(), None)]);
+        assert_eq!(seen.len(), 1);
+
+        let out: Vec<Result<(Did, Option<String>), ()>> =
+            seen.mark(stream::iter(out).map(Ok)).collect().await;
+        assert_eq!(out, vec![Ok((b, None))]);
+        assert_eq!(seen.len(), 2);
+    }
+
+    #[test]
+    fn test_intersection() {
+        let a: Did = "did:plc:aaaaaaaaaaaaaaaaaaaaaaaa".parse().unwrap();
+        let b: Did = "did:plc:bbbbbbbbbbbbbbbbbbbbbbbb".parse().unwrap();
+        let listed = SeenSet::from_dids([a.clone(), b.clone()]);
+        let blocked = SeenSet::from_dids([b.clone()]);
+
+        let seen = SeenSet::intersection(&[listed.clone(), blocked]);
+        assert!(seen.contains(&b) && !seen.contains(&a));
+        assert_eq!(SeenSet::intersection(&[listed]).len(), 2);
+        assert!(SeenSet::intersection(&[]).is_empty());
+    }
+}
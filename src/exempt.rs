@@ -0,0 +1,269 @@
+//! Accounts that must never be added to a list
+//!
+//! Exempted dids come from an explicit file (handles or dids, one per line),
+//! the accounts the list owner follows (or only the mutuals), and the members
+//! of an allowlist. The set is recomputed periodically, see [`Exemptions::refresh_every`].
+
+use std::{collections::HashSet, error::Error, fs, path::PathBuf, sync::RwLock, time::Duration};
+
+use atrium_api::{
+    agent::store::SessionStore,
+    app::bsky::graph::get_follows,
+    types::{
+        string::{AtIdentifier, Did},
+        LimitedNonZeroU8,
+    },
+    xrpc::XrpcClient,
+};
+use bsky_sdk::BskyAgent;
+use clap::Args;
+use futures_core::Stream;
+use futures_util::{future, StreamExt, TryStreamExt};
+use ipld_core::ipld::Ipld;
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::{followers::from_followers, list::List, resolver};
+
+/// Where exempted accounts come from.
+#[derive(Debug, Clone, Default)]
+pub struct ExemptionConfig {
+    /// file with one handle, did or profile link per line
+    pub file: Option<PathBuf>,
+    /// everyone the owner follows
+    pub follows: bool,
+    /// those the owner follows that follow them back
+    pub mutuals: bool,
+    /// list whose members are exempted
+    pub allowlist: Option<String>,
+}
+
+impl ExemptionConfig {
+    pub fn is_empty(&self) -> bool {
+        self.file.is_none() && !self.follows && !self.mutuals && self.allowlist.is_none()
+    }
+}
+
+/// Command line flags shared by the watchers.
+#[derive(Args, Debug, Clone)]
+pub struct ExemptArgs {
+    /// never add accounts listed in this file: handles, dids or profile links, one per line
+    #[arg(long, env)]
+    exempt_file: Option<PathBuf>,
+
+    /// never add accounts the list owner follows
+    #[arg(long, default_value = "false")]
+    exempt_follows: bool,
+
+    /// never add mutuals of the list owner
+    #[arg(long, default_value = "false")]
+    exempt_mutuals: bool,
+
+    /// never add members of this list: AT-URI or bsky.app link
+    #[arg(long, env)]
+    allowlist: Option<String>,
+
+    /// seconds between two refreshes of the exempted accounts
+    #[arg(long, default_value = "3600")]
+    exempt_refresh: u64,
+}
+
+impl ExemptArgs {
+    pub fn refresh_period(&self) -> Duration {
+        Duration::from_secs(self.exempt_refresh)
+    }
+
+    /// Resolves the allowlist link.
+    pub async fn config<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+    ) -> Result<ExemptionConfig, Box<dyn Error>> {
+        let allowlist = match &self.allowlist {
+            Some(list) => Some(resolver::resolve_list(agent, list).await?),
+            None => None,
+        };
+        Ok(ExemptionConfig {
+            file: self.exempt_file.clone(),
+            follows: self.exempt_follows,
+            mutuals: self.exempt_mutuals,
+            allowlist,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Exemptions {
+    owner: Did,
+    config: ExemptionConfig,
+    dids: RwLock<HashSet<Did>>,
+}
+
+/// Everyone `actor` follows.
+async fn get_all_follows<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+    agent: &BskyAgent<T, S>,
+    actor: &Did,
+) -> Result<HashSet<Did>, Box<dyn Error>> {
+    let mut follows = HashSet::new();
+    let mut cursor = None;
+    loop {
+        let batch = agent
+            .api
+            .app
+            .bsky
+            .graph
+            .get_follows(get_follows::Parameters {
+                data: get_follows::ParametersData {
+                    actor: AtIdentifier::Did(actor.clone()),
+                    cursor,
+                    limit: Some(LimitedNonZeroU8::MAX),
+                },
+                extra_data: Ipld::Null,
+            })
+            .await?;
+        follows.extend(batch.data.follows.into_iter().map(|f| f.data.did));
+        cursor = batch.data.cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    Ok(follows)
+}
+
+impl Exemptions {
+    /// The owner is always exempted, call [`Exemptions::refresh`] to load the rest.
+    pub fn new(owner: Did, config: ExemptionConfig) -> Self {
+        let dids = RwLock::new(HashSet::from([owner.clone()]));
+        Self {
+            owner,
+            config,
+            dids,
+        }
+    }
+
+    /// Exemptions of the logged-in account, loaded from all sources.
+    pub async fn load<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        agent: &BskyAgent<T, S>,
+        config: ExemptionConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        let owner = agent.get_session().await.ok_or("not logged in")?.data.did;
+        let exemptions = Self::new(owner, config);
+        exemptions.refresh(agent).await?;
+        Ok(exemptions)
+    }
+
+    /// Whether there's anything to refresh.
+    pub fn is_refreshable(&self) -> bool {
+        !self.config.is_empty()
+    }
+
+    pub fn is_exempt(&self, did: &Did) -> bool {
+        self.dids.read().unwrap().contains(did)
+    }
+
+    pub fn len(&self) -> usize {
+        self.dids.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drops exempted dids from a stream of (did, cursor), for sources writing without a
+    /// [`WriteQueue`](crate::writer::WriteQueue).
+    pub fn filter<'a, C: 'a>(
+        &'a self,
+        dids: impl Stream<Item = (Did, C)> + 'a,
+    ) -> impl Stream<Item = (Did, C)> + 'a {
+        dids.filter(move |(did, _)| {
+            let exempt = self.is_exempt(did);
+            if exempt {
+                info!(msg = "exempted, not adding", did = ?did);
+            }
+            future::ready(!exempt)
+        })
+    }
+
+    /// Recomputes the exempted dids from all sources.
+    pub async fn refresh<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut dids = HashSet::from([self.owner.clone()]);
+
+        if let Some(path) = &self.config.file {
+            for line in fs::read_to_string(path)?.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                dids.insert(resolver::resolve_actor(agent, line).await?);
+            }
+        }
+
+        if self.config.follows || self.config.mutuals {
+            let follows = get_all_follows(agent, &self.owner).await?;
+            if self.config.follows {
+                dids.extend(follows);
+            } else {
+                let followers: HashSet<Did> =
+                    from_followers(agent, AtIdentifier::Did(self.owner.clone()), None)
+                        .await
+                        .map_ok(|(f, _)| f.data.did)
+                        .try_collect()
+                        .await?;
+                dids.extend(follows.intersection(&followers).cloned());
+            }
+        }
+
+        if let Some(list) = &self.config.allowlist {
+            let members: Vec<Did> = List::get_members(list.clone(), agent, None)
+                .await
+                .map_ok(|m| m.did)
+                .try_collect()
+                .await?;
+            dids.extend(members);
+        }
+
+        info!(msg = "refreshed exemptions", nb = dids.len());
+        *self.dids.write().unwrap() = dids;
+        Ok(())
+    }
+
+    /// Refreshes forever, keeping the previous set when a refresh fails.
+    /// Never returns, even when there is nothing to refresh.
+    pub async fn refresh_every<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        period: Duration,
+    ) {
+        if !self.is_refreshable() {
+            return std::future::pending().await;
+        }
+        let mut interval = time::interval(period);
+        // first tick is immediate, exemptions are expected to be loaded already
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.refresh(agent).await {
+                warn!(msg = "could not refresh exemptions", err = %e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use atrium_api::types::string::Did;
+
+    use super::{ExemptionConfig, Exemptions};
+
+    #[test]
+    fn test_owner_exempted() {
+        let owner: Did = "did:plc:aaaaaaaaaaaaaaaaaaaaaaaa".parse().unwrap();
+        let other: Did = "did:plc:bbbbbbbbbbbbbbbbbbbbbbbb".parse().unwrap();
+        let exemptions = Exemptions::new(owner.clone(), ExemptionConfig::default());
+        assert!(exemptions.is_exempt(&owner));
+        assert!(!exemptions.is_exempt(&other));
+        assert!(ExemptionConfig::default().is_empty());
+    }
+}
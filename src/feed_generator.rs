@@ -5,6 +5,7 @@
 use std::error::Error;
 
 use atrium_api::{
+    agent::store::SessionStore,
     app::bsky::{actor::defs::ProfileViewBasicData, feed::get_feed},
     xrpc::XrpcClient,
 };
@@ -12,8 +13,8 @@ use bsky_sdk::BskyAgent;
 use ipld_core::ipld::Ipld;
 use tracing::info;
 
-pub async fn from_feed<T: XrpcClient + Send + Sync>(
-    agent: &BskyAgent<T>,
+pub async fn from_feed<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+    agent: &BskyAgent<T, S>,
     feed: String,
 ) -> Result<Vec<ProfileViewBasicData>, Box<dyn Error>> {
     let gf = agent.api.app.bsky.feed.get_feed(get_feed::Parameters {
@@ -1,21 +1,76 @@
 //! from account
+//!
+//! Pages are read until the last one or the first error, which is yielded:
+//! deactivated, taken down or blocking accounts can't be read.
+
+use std::{error::Error, fmt, future, sync::Mutex};
 
 use async_stream::stream;
 use atrium_api::{
-    app::bsky::{actor::defs::ProfileViewData, graph::get_followers},
+    agent::store::SessionStore,
+    app::bsky::{
+        actor::defs::ProfileViewData,
+        graph::{get_followers, get_follows},
+    },
     types::{string::AtIdentifier, LimitedNonZeroU8, Object},
     xrpc::XrpcClient,
 };
 use bsky_sdk::BskyAgent;
 use futures_core::Stream;
+use futures_util::StreamExt;
 use ipld_core::ipld::Ipld;
 use tracing::info;
 
-pub async fn from_followers<T: XrpcClient + Send + Sync>(
-    agent: &BskyAgent<T>,
+/// Error reading a page, kept as a message so that streams can be sent across tasks.
+#[derive(Debug)]
+pub struct PageError(pub String);
+
+impl fmt::Display for PageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for PageError {}
+
+/// Ends a stream of results at the first error, kept in `error` for the caller to return.
+pub fn until_error<'a, T: 'a>(
+    items: impl Stream<Item = Result<T, PageError>> + 'a,
+    error: &'a Mutex<Option<PageError>>,
+) -> impl Stream<Item = T> + 'a {
+    items.scan((), move |_, item| {
+        future::ready(match item {
+            Ok(item) => Some(item),
+            Err(e) => {
+                *error.lock().unwrap() = Some(e);
+                None
+            }
+        })
+    })
+}
+
+/// Yields the error kept by [`until_error`] once `items` end, so that consumers
+/// know they stopped early.
+pub fn then_error<'a, T: 'a>(
+    items: impl Stream<Item = Result<T, PageError>> + 'a,
+    error: &'a Mutex<Option<PageError>>,
+) -> impl Stream<Item = Result<T, PageError>> + 'a {
+    stream! {
+        for await item in items {
+            yield item;
+        }
+        let error = error.lock().unwrap().take();
+        if let Some(e) = error {
+            yield Err(e);
+        }
+    }
+}
+
+pub async fn from_followers<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+    agent: &BskyAgent<T, S>,
     actor: AtIdentifier,
     cursor: Option<String>,
-) -> impl Stream<Item = (Object<ProfileViewData>, Option<String>)> + '_ {
+) -> impl Stream<Item = Result<(Object<ProfileViewData>, Option<String>), PageError>> + '_ {
     let get_batch = |actor: AtIdentifier, cursor: Option<_>| async {
         agent
             .api
@@ -36,12 +91,64 @@ pub async fn from_followers<T: XrpcClient + Send + Sync>(
     stream! {
         let mut cursor = cursor;
         for i in 0.. {
-            let batch = get_batch(actor.clone(), cursor).await.unwrap();
+            let batch = match get_batch(actor.clone(), cursor).await {
+                Ok(batch) => batch,
+                Err(e) => {
+                    yield Err(PageError(e.to_string()));
+                    break;
+                }
+            };
             info!(msg="getting batch", nb=i, cursor=?batch.cursor);
             cursor = batch.cursor.clone();
             info!(msg="got followers", nb=&batch.data.followers.len());
             for follower in batch.data.followers {
-                yield (follower, cursor.clone());
+                yield Ok((follower, cursor.clone()));
+            }
+            if cursor.is_none() {
+                break;
+            }
+        }
+    }
+}
+
+/// Everyone `actor` follows, same contract as [`from_followers`].
+pub async fn from_follows<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+    agent: &BskyAgent<T, S>,
+    actor: AtIdentifier,
+    cursor: Option<String>,
+) -> impl Stream<Item = Result<(Object<ProfileViewData>, Option<String>), PageError>> + '_ {
+    let get_batch = |actor: AtIdentifier, cursor: Option<_>| async {
+        agent
+            .api
+            .app
+            .bsky
+            .graph
+            .get_follows(get_follows::Parameters {
+                data: get_follows::ParametersData {
+                    actor,
+                    cursor,
+                    limit: Some(LimitedNonZeroU8::MAX),
+                },
+                extra_data: Ipld::Null,
+            })
+            .await
+    };
+
+    stream! {
+        let mut cursor = cursor;
+        for i in 0.. {
+            let batch = match get_batch(actor.clone(), cursor).await {
+                Ok(batch) => batch,
+                Err(e) => {
+                    yield Err(PageError(e.to_string()));
+                    break;
+                }
+            };
+            info!(msg="getting batch", nb=i, cursor=?batch.cursor);
+            cursor = batch.cursor.clone();
+            info!(msg="got follows", nb=&batch.data.follows.len());
+            for follow in batch.data.follows {
+                yield Ok((follow, cursor.clone()));
             }
             if cursor.is_none() {
                 break;
@@ -72,11 +179,11 @@ mod tests {
             .unwrap();
 
         let actor = AtIdentifier::Handle(Handle::new("cnews.bsky.social".into()).unwrap());
-        let mut followers = from_followers(&agent, actor, None).await.take(5);
+        let followers = from_followers(&agent, actor, None).await.take(5);
         pin_mut!(followers);
 
         while let Some(x) = followers.next().await {
-            dbg!(&x.0.handle);
+            dbg!(&x.unwrap().0.handle);
         }
     }
 }
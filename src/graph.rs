@@ -0,0 +1,214 @@
+//! from followers of followers
+//!
+//! Walks the follower graph breadth first from a root account, reading at
+//! most [`Limits::per_node`] followers of each account and
+//! [`Limits::total`] overall. Accounts to expand are kept in the
+//! [`StateStore`] along with their cursor, so that a walk can be resumed.
+//! Accounts whose followers can't be read are skipped, those failing on
+//! transient errors are tried again later.
+
+use async_stream::stream;
+use atrium_api::{
+    agent::store::SessionStore,
+    types::string::{AtIdentifier, Did},
+    xrpc::XrpcClient,
+};
+use bsky_sdk::BskyAgent;
+use futures_core::Stream;
+use futures_util::{pin_mut, StreamExt};
+use std::time::Duration;
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::{
+    followers::{from_followers, PageError},
+    list::List,
+    session,
+    state::{State, StateStore},
+};
+
+/// First wait after a transient error, doubled on each one in a row.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Longest wait between two attempts.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// followers of the root are at depth 1
+    pub depth: usize,
+    pub per_node: usize,
+    pub total: usize,
+}
+
+/// A follower found by the walk.
+#[derive(Debug, Clone)]
+pub struct Discovered {
+    pub did: Did,
+    /// accounts it was reached through, root first, ending with the one it follows
+    pub path: Vec<Did>,
+    /// cursor of the next page of followers of the account it follows
+    pub cursor: Option<String>,
+}
+
+impl Discovered {
+    pub fn depth(&self) -> usize {
+        self.path.len()
+    }
+}
+
+/// Shallowest account left to expand, within the depth limit.
+fn next_node(nodes: Vec<(Did, State)>, depth: usize) -> Option<(Did, State)> {
+    nodes
+        .into_iter()
+        .filter(|(_, s)| s.walk().is_some_and(|w| !w.done && w.path.len() < depth))
+        .min_by_key(|(_, s)| s.walk().map(|w| w.path.len()))
+}
+
+/// Followers, followers of followers... of `root`, up to `limits`.
+///
+/// Progress is kept in `states` once a page has been yielded: save them
+/// once what was yielded before has been written. Followers reached
+/// through several paths are yielded each time.
+///
+/// Ends with an auth error, if the session expires: walking again once logged
+/// in resumes from there.
+pub fn from_graph<'a, T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+    agent: &'a BskyAgent<T, S>,
+    root: Did,
+    modlist: List,
+    limits: Limits,
+    states: &'a StateStore,
+) -> impl Stream<Item = Result<Discovered, PageError>> + 'a {
+    stream! {
+        let mut delay = RETRY_DELAY;
+        states.insert_walk(&root, modlist.clone(), vec![]);
+        let mut total: usize = states
+            .walk_nodes()
+            .iter()
+            .filter_map(|(_, s)| s.walk().map(|w| w.read))
+            .sum();
+
+        while total < limits.total {
+            let Some((node, state)) = next_node(states.walk_nodes(), limits.depth) else {
+                info!(msg = "graph walk done", read = total);
+                break;
+            };
+            let walk = state.walk().cloned().unwrap_or_default();
+            let mut path = walk.path;
+            path.push(node.clone());
+            // followers are only expanded if theirs are within the depth limit
+            let expand = path.len() < limits.depth;
+            info!(msg = "expanding", did = ?node, depth = path.len() - 1, start_cursor = state.cursor());
+
+            let followers = from_followers(
+                agent,
+                AtIdentifier::Did(node.clone()),
+                state.cursor().map(String::from),
+            )
+            .await;
+            pin_mut!(followers);
+
+            let mut read = walk.read;
+            let mut last_cursor: Option<String> = None;
+            let mut children = vec![];
+            let mut done = true;
+            let mut failed = false;
+            while let Some(item) = followers.next().await {
+                let (follower, cursor) = match item {
+                    Ok(item) => item,
+                    Err(e) if session::is_auth_error(&e) => {
+                        // every account would be skipped: resuming reads this page again
+                        warn!(msg = "session expired, stopping the walk", err = %e);
+                        yield Err(e);
+                        return;
+                    }
+                    Err(e) if session::is_transient(&e) => {
+                        // the pages read are done, the account is expanded again later
+                        warn!(msg = "could not read followers, retrying", did = ?node, err = %e, delay = ?delay);
+                        failed = true;
+                        break;
+                    }
+                    Err(e) => {
+                        // e.g. deactivated or blocking: moves on to the next account
+                        warn!(msg = "could not read followers, skipping", did = ?node, err = %e);
+                        break;
+                    }
+                };
+                if read >= limits.per_node {
+                    break;
+                }
+                if total >= limits.total {
+                    done = false;
+                    break;
+                }
+                // the previous page has been yielded
+                if cursor != last_cursor {
+                    for child in children.drain(..) {
+                        states.insert_walk(&child, modlist.clone(), path.clone());
+                    }
+                    states.set_walk_progress(&node, last_cursor.clone(), read, false);
+                    last_cursor = cursor.clone();
+                }
+                read += 1;
+                total += 1;
+                if expand {
+                    children.push(follower.did.clone());
+                }
+                delay = RETRY_DELAY;
+                yield Ok(Discovered {
+                    did: follower.data.did,
+                    path: path.clone(),
+                    cursor,
+                });
+            }
+            for child in children {
+                states.insert_walk(&child, modlist.clone(), path.clone());
+            }
+            if failed {
+                let cursor = last_cursor.or(state.cursor().map(String::from));
+                states.set_walk_progress(&node, cursor, read, false);
+                time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+                continue;
+            }
+            // an interrupted page is read again on resume
+            states.set_walk_progress(&node, None, read, done);
+        }
+        if total >= limits.total {
+            info!(msg = "global cap reached", read = total);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use atrium_api::types::string::Did;
+
+    use super::next_node;
+    use crate::{list::List, state::StateStore};
+
+    #[test]
+    fn test_next_node() {
+        let states = StateStore::load("/nonexistent/graph.json").unwrap();
+        let modlist = List::new("at://did:plc:aaaaaaaaaaaaaaaaaaaaaaaa/l/1".to_string());
+        let did = |c: char| -> Did {
+            format!("did:plc:{}", c.to_string().repeat(24))
+                .parse()
+                .unwrap()
+        };
+        let (root, a, b) = (did('r'), did('a'), did('b'));
+
+        states.insert_walk(&root, modlist.clone(), vec![]);
+        assert_eq!(next_node(states.walk_nodes(), 2).unwrap().0, root);
+        // nothing left once the root is done
+        states.set_walk_progress(&root, None, 10, true);
+        assert!(next_node(states.walk_nodes(), 2).is_none());
+
+        states.insert_walk(&b, modlist.clone(), vec![root.clone(), a.clone()]);
+        states.insert_walk(&a, modlist.clone(), vec![root.clone()]);
+        assert!(!states.insert_walk(&a, modlist, vec![]));
+        assert_eq!(next_node(states.walk_nodes(), 3).unwrap().0, a);
+        assert!(next_node(states.walk_nodes(), 1).is_none());
+    }
+}
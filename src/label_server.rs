@@ -0,0 +1,656 @@
+//! Labels as an output
+//!
+//! Modlists are all or nothing for their subscribers: a label (e.g. `follows-x`)
+//! lets them choose whether to hide, warn or ignore. Labels are signed with the
+//! labeler key, appended to a local log and served on
+//! `com.atproto.label.subscribeLabels` and `com.atproto.label.queryLabels` by a
+//! small built-in http server.
+//!
+//! The labeler is the logged-in account: its did document must list the public
+//! key logged on start as `#atproto_label`, and the server as `#atproto_labeler`.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use atrium_api::types::string::{Datetime, Did};
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use ciborium::Value;
+use clap::Args;
+use futures_util::{SinkExt, StreamExt};
+use k256::ecdsa::{signature::Signer, Signature, SigningKey};
+use rand_core::OsRng;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    select,
+    sync::broadcast::{self, error::RecvError},
+};
+use tokio_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role, Message},
+    WebSocketStream,
+};
+use tracing::{info, warn};
+use url::Url;
+
+use crate::labeler::Label;
+
+/// Labels kept for slow subscribers before they get disconnected.
+const BROADCAST_SIZE: usize = 1000;
+
+/// Max size of a request line and its headers.
+const MAX_HEADERS: usize = 16 * 1024;
+
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 250;
+
+/// Multicodec prefix of compressed secp256k1 public keys.
+const SECP256K1_PUB: [u8; 2] = [0xe7, 0x01];
+
+/// Command line flags of the built-in labeler.
+#[derive(Args, Debug, Clone)]
+pub struct LabelArgs {
+    /// label to put on accounts, e.g. follows-x, served by a built-in labeler
+    #[arg(long)]
+    label: Option<String>,
+
+    /// address the labeler listens on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    serve: SocketAddr,
+
+    /// hex encoded secp256k1 key signing labels, created if missing
+    #[arg(long, default_value = "labeler.key")]
+    signing_key: PathBuf,
+
+    /// emitted labels, one json per line
+    #[arg(long, default_value = "labels.jsonl")]
+    label_log: PathBuf,
+}
+
+impl LabelArgs {
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// Opens the labeler and starts serving it, if a label was asked for.
+    pub async fn open(&self, labeler: Did) -> Result<Option<LabelOutput>, Box<dyn Error>> {
+        let Some(val) = &self.label else {
+            return Ok(None);
+        };
+        let key = load_key(&self.signing_key)?;
+        let server = Arc::new(LabelServer::open(labeler, key, &self.label_log)?);
+        let listener = TcpListener::bind(self.serve).await?;
+        info!(msg = "serving labels", addr = %self.serve, label = val);
+        tokio::spawn(server.clone().serve(listener));
+        Ok(Some(LabelOutput::new(server, val.clone())))
+    }
+}
+
+/// Reads a hex encoded signing key, creating one if the file doesn't exist.
+pub fn load_key(path: &Path) -> Result<SigningKey, Box<dyn Error>> {
+    match fs::read_to_string(path) {
+        Ok(hex) => {
+            let hex = hex.trim();
+            let bytes = (0..hex.len())
+                .step_by(2)
+                .map(|i| {
+                    hex.get(i..i + 2)
+                        .and_then(|b| u8::from_str_radix(b, 16).ok())
+                })
+                .collect::<Option<Vec<u8>>>()
+                .ok_or("invalid signing key")?;
+            Ok(SigningKey::from_slice(&bytes)?)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let key = SigningKey::random(&mut OsRng);
+            let hex: String = key.to_bytes().iter().map(|b| format!("{b:02x}")).collect();
+            fs::write(path, hex)?;
+            info!(msg = "created signing key", location = ?path);
+            Ok(key)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Public key to put in the did document, as `publicKeyMultibase`.
+pub fn public_key_multibase(key: &SigningKey) -> String {
+    let point = key.verifying_key().to_encoded_point(true);
+    multibase::encode(
+        multibase::Base::Base58Btc,
+        [&SECP256K1_PUB[..], point.as_bytes()].concat(),
+    )
+}
+
+/// DAG-CBOR map of a label: keys are sorted by length then bytes, and all are 3 long.
+fn encode(label: &Label, sig: Option<&[u8]>) -> Value {
+    let text = |s: &str| Value::Text(s.to_string());
+    let mut fields = vec![("cts", text(&label.cts))];
+    if let Some(exp) = &label.exp {
+        fields.push(("exp", text(exp)));
+    }
+    if label.is_negation() {
+        fields.push(("neg", Value::Bool(true)));
+    }
+    if let Some(sig) = sig {
+        fields.push(("sig", Value::Bytes(sig.to_vec())));
+    }
+    fields.extend([
+        ("src", text(label.src.as_str())),
+        ("uri", text(&label.uri)),
+        ("val", text(&label.val)),
+        ("ver", Value::Integer(1.into())),
+    ]);
+    Value::Map(fields.into_iter().map(|(k, v)| (text(k), v)).collect())
+}
+
+fn to_cbor(value: &Value) -> Vec<u8> {
+    let mut bytes = vec![];
+    ciborium::into_writer(value, &mut bytes).expect("writing to a vec");
+    bytes
+}
+
+/// Signs the DAG-CBOR of a label without its signature, low-S as required by atproto.
+fn sign(key: &SigningKey, label: &Label) -> Vec<u8> {
+    let sig: Signature = key.sign(&to_cbor(&encode(label, None)));
+    sig.normalize_s().unwrap_or(sig).to_bytes().to_vec()
+}
+
+/// A `subscribeLabels` frame: a header then a body.
+fn frame(t: Option<&str>, body: Value) -> Vec<u8> {
+    let header = match t {
+        Some(t) => vec![("t", Value::Text(t.to_string())), ("op", 1.into())],
+        None => vec![("op", (-1).into())],
+    };
+    let header = Value::Map(
+        header
+            .into_iter()
+            .map(|(k, v)| (Value::Text(k.to_string()), v))
+            .collect(),
+    );
+    [to_cbor(&header), to_cbor(&body)].concat()
+}
+
+fn error_frame(error: &str, message: &str) -> Vec<u8> {
+    frame(
+        None,
+        Value::Map(vec![
+            ("error".into(), error.into()),
+            ("message".into(), message.into()),
+        ]),
+    )
+}
+
+/// A label as emitted, with its sequence number and signature.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Emitted {
+    pub seq: i64,
+    pub label: Label,
+    /// base64, without padding
+    sig: String,
+}
+
+impl Emitted {
+    pub fn sig(&self) -> Result<Vec<u8>, base64::DecodeError> {
+        STANDARD_NO_PAD.decode(&self.sig)
+    }
+
+    fn frame(&self) -> Vec<u8> {
+        let sig = self.sig().unwrap_or_default();
+        frame(
+            Some("#labels"),
+            Value::Map(vec![
+                ("seq".into(), self.seq.into()),
+                (
+                    "labels".into(),
+                    Value::Array(vec![encode(&self.label, Some(&sig))]),
+                ),
+            ]),
+        )
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let mut label = json!(self.label);
+        label["ver"] = 1.into();
+        label["sig"] = json!({ "$bytes": self.sig });
+        label
+    }
+}
+
+/// Emitted labels, and the latest one per account and value.
+#[derive(Debug)]
+struct Log {
+    file: File,
+    emitted: Vec<Emitted>,
+    latest: HashMap<(String, String), usize>,
+}
+
+impl Log {
+    fn push(&mut self, emitted: Emitted) {
+        let key = (emitted.label.uri.clone(), emitted.label.val.clone());
+        self.latest.insert(key, self.emitted.len());
+        self.emitted.push(emitted);
+    }
+
+    fn seq(&self) -> i64 {
+        self.emitted.last().map_or(0, |e| e.seq)
+    }
+
+    fn is_applied(&self, uri: &str, val: &str) -> bool {
+        self.latest
+            .get(&(uri.to_string(), val.to_string()))
+            .is_some_and(|i| !self.emitted[*i].label.is_negation())
+    }
+
+    fn since(&self, seq: i64) -> Vec<Emitted> {
+        let start = self.emitted.partition_point(|e| e.seq <= seq);
+        self.emitted[start..].to_vec()
+    }
+
+    /// Labels not negated since, oldest first.
+    fn applied(&self) -> Vec<&Emitted> {
+        let mut indices: Vec<usize> = self.latest.values().copied().collect();
+        indices.sort_unstable();
+        indices
+            .into_iter()
+            .map(|i| &self.emitted[i])
+            .filter(|e| !e.label.is_negation())
+            .collect()
+    }
+}
+
+struct Request {
+    target: String,
+    headers: HashMap<String, String>,
+}
+
+/// Reads the request line and headers of a GET request.
+async fn read_request(socket: &mut TcpStream) -> Result<Request, Box<dyn Error>> {
+    let mut buf = vec![];
+    let mut chunk = [0; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() > MAX_HEADERS {
+            return Err("headers too large".into());
+        }
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 {
+            return Err("connection closed".into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let text = String::from_utf8_lossy(&buf);
+    let mut lines = text.split("\r\n");
+    let target = lines
+        .next()
+        .and_then(|l| l.strip_prefix("GET "))
+        .and_then(|l| l.split(' ').next())
+        .ok_or("only GET is supported")?
+        .to_string();
+    let headers = lines
+        .take_while(|l| !l.is_empty())
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_ascii_lowercase(), v.trim().to_string()))
+        .collect();
+    Ok(Request { target, headers })
+}
+
+async fn respond(
+    socket: &mut TcpStream,
+    status: &str,
+    body: serde_json::Value,
+) -> Result<(), Box<dyn Error>> {
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nAccess-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    socket.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// Signs, logs and serves the labels of a labeler.
+pub struct LabelServer {
+    labeler: Did,
+    key: SigningKey,
+    log: Mutex<Log>,
+    live: broadcast::Sender<Emitted>,
+}
+
+impl LabelServer {
+    /// Loads the labels emitted so far, starting from scratch if there's no log yet.
+    pub fn open(
+        labeler: Did,
+        key: SigningKey,
+        path: impl AsRef<Path>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let mut emitted = vec![];
+        if let Ok(file) = File::open(path) {
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                if !line.trim().is_empty() {
+                    emitted.push(serde_json::from_str::<Emitted>(&line)?);
+                }
+            }
+        }
+        let mut log = Log {
+            file: OpenOptions::new().create(true).append(true).open(path)?,
+            emitted: vec![],
+            latest: HashMap::new(),
+        };
+        emitted.into_iter().for_each(|e| log.push(e));
+        info!(
+            msg = "loaded labels",
+            nb = log.emitted.len(),
+            seq = log.seq(),
+            labeler = labeler.as_str(),
+            public_key = public_key_multibase(&key)
+        );
+        let (live, _) = broadcast::channel(BROADCAST_SIZE);
+        Ok(Self {
+            labeler,
+            key,
+            log: Mutex::new(log),
+            live,
+        })
+    }
+
+    pub fn labeler(&self) -> &Did {
+        &self.labeler
+    }
+
+    /// Signs, logs then broadcasts a label, or its negation.
+    ///
+    /// Gives its sequence number, None when it changes nothing: adding an
+    /// applied label, or negating one that isn't.
+    pub fn emit(&self, uri: &str, val: &str, neg: bool) -> Result<Option<i64>, Box<dyn Error>> {
+        let mut log = self.log.lock().unwrap();
+        if log.is_applied(uri, val) != neg {
+            return Ok(None);
+        }
+        let label = Label {
+            src: self.labeler.clone(),
+            uri: uri.to_string(),
+            val: val.to_string(),
+            neg: neg.then_some(true),
+            cts: Datetime::now().as_str().to_string(),
+            exp: None,
+        };
+        let emitted = Emitted {
+            seq: log.seq() + 1,
+            sig: STANDARD_NO_PAD.encode(sign(&self.key, &label)),
+            label,
+        };
+        // logged before being served, so that subscribers never see a label that gets lost
+        writeln!(log.file, "{}", serde_json::to_string(&emitted)?)?;
+        log.push(emitted.clone());
+        // having no subscriber is fine
+        let _ = self.live.send(emitted.clone());
+        Ok(Some(emitted.seq))
+    }
+
+    /// Accounts currently labeled with `val`.
+    pub fn labeled(&self, val: &str) -> Vec<Did> {
+        self.log
+            .lock()
+            .unwrap()
+            .applied()
+            .into_iter()
+            .filter(|e| e.label.val == val)
+            .filter_map(|e| e.label.account())
+            .collect()
+    }
+
+    /// Serves `subscribeLabels` and `queryLabels`, one task per connection.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (socket, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!(msg = "could not accept connection", err = %e);
+                    continue;
+                }
+            };
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.handle(socket).await.map_err(|e| e.to_string()) {
+                    warn!(msg = "labeler connection failed", addr = %addr, err = e);
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, mut socket: TcpStream) -> Result<(), Box<dyn Error>> {
+        let request = read_request(&mut socket).await?;
+        let url = Url::parse("http://localhost/")?.join(&request.target)?;
+        match url.path() {
+            "/xrpc/com.atproto.label.subscribeLabels" => {
+                let Some(key) = request.headers.get("sec-websocket-key") else {
+                    let error =
+                        json!({"error": "InvalidRequest", "message": "expected a websocket"});
+                    return respond(&mut socket, "400 Bad Request", error).await;
+                };
+                let cursor = url
+                    .query_pairs()
+                    .find(|(k, _)| k == "cursor")
+                    .and_then(|(_, v)| v.parse().ok());
+                let accept = derive_accept_key(key.as_bytes());
+                let response = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {accept}\r\n\r\n");
+                socket.write_all(response.as_bytes()).await?;
+                let ws = WebSocketStream::from_raw_socket(socket, Role::Server, None).await;
+                self.subscribe(ws, cursor).await
+            }
+            "/xrpc/com.atproto.label.queryLabels" => {
+                respond(&mut socket, "200 OK", self.query(&url)).await
+            }
+            path => {
+                let error = json!({"error": "MethodNotImplemented", "message": path});
+                respond(&mut socket, "404 Not Found", error).await
+            }
+        }
+    }
+
+    /// Sends labels after `cursor`, if any, then live ones.
+    async fn subscribe(
+        &self,
+        mut ws: WebSocketStream<TcpStream>,
+        cursor: Option<i64>,
+    ) -> Result<(), Box<dyn Error>> {
+        // subscribed before replaying, so that nothing is missed in between
+        let mut live = self.live.subscribe();
+        let mut last = 0;
+        if let Some(cursor) = cursor {
+            let (backlog, seq) = {
+                let log = self.log.lock().unwrap();
+                (log.since(cursor), log.seq())
+            };
+            if cursor > seq {
+                let error = error_frame("FutureCursor", "cursor is ahead of the labeler");
+                ws.send(Message::binary(error)).await?;
+                ws.close(None).await?;
+                return Ok(());
+            }
+            for e in &backlog {
+                ws.send(Message::binary(e.frame())).await?;
+            }
+            last = backlog.last().map_or(cursor, |e| e.seq);
+        }
+        loop {
+            select! {
+                emitted = live.recv() => match emitted {
+                    Ok(e) if e.seq > last => {
+                        last = e.seq;
+                        ws.send(Message::binary(e.frame())).await?;
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(_)) => {
+                        // it can reconnect from its last sequence number
+                        let error = error_frame("ConsumerTooSlow", "too many labels behind");
+                        ws.send(Message::binary(error)).await?;
+                        return Ok(());
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                // reading also answers pings
+                message = ws.next() => match message {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return Ok(()),
+                    Some(Ok(_)) => {}
+                },
+            }
+        }
+    }
+
+    /// Labels currently applied, oldest first, see `com.atproto.label.queryLabels`.
+    fn query(&self, url: &Url) -> serde_json::Value {
+        let (mut patterns, mut sources) = (vec![], vec![]);
+        let (mut limit, mut cursor) = (DEFAULT_LIMIT, 0);
+        for (k, v) in url.query_pairs() {
+            match k.as_ref() {
+                "uriPatterns" => patterns.push(v.into_owned()),
+                "sources" => sources.push(v.into_owned()),
+                "limit" => limit = v.parse().unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT),
+                "cursor" => cursor = v.parse().unwrap_or(0),
+                _ => {}
+            }
+        }
+        let matches = |uri: &str| {
+            patterns.iter().any(|p| match p.strip_suffix('*') {
+                Some(prefix) => uri.starts_with(prefix),
+                None => uri == p,
+            })
+        };
+        let ours = sources.is_empty() || sources.iter().any(|s| s == self.labeler.as_str());
+
+        let page: Vec<Emitted> = match ours {
+            true => self
+                .log
+                .lock()
+                .unwrap()
+                .applied()
+                .into_iter()
+                .filter(|e| e.seq > cursor && matches(&e.label.uri))
+                .take(limit)
+                .cloned()
+                .collect(),
+            false => vec![],
+        };
+        let labels: Vec<serde_json::Value> = page.iter().map(Emitted::to_json).collect();
+        let mut output = json!({ "labels": labels });
+        // a full page may not be the last one
+        if let Some(last) = page.last().filter(|_| page.len() == limit) {
+            output["cursor"] = last.seq.to_string().into();
+        }
+        output
+    }
+}
+
+/// Writes dids as a label on the account rather than as list entries, see [`crate::output::Output`].
+#[derive(Clone)]
+pub struct LabelOutput {
+    server: Arc<LabelServer>,
+    val: String,
+}
+
+impl LabelOutput {
+    pub fn new(server: Arc<LabelServer>, val: String) -> Self {
+        Self { server, val }
+    }
+
+    pub fn val(&self) -> &str {
+        &self.val
+    }
+
+    /// Labels an account, does nothing if it already is.
+    pub fn label(&self, did: &Did) -> Result<(), Box<dyn Error>> {
+        self.server.emit(did.as_str(), &self.val, false).map(|_| ())
+    }
+
+    /// Negates the label of an account, does nothing if it has none.
+    pub fn negate(&self, did: &Did) -> Result<(), Box<dyn Error>> {
+        self.server.emit(did.as_str(), &self.val, true).map(|_| ())
+    }
+
+    /// Accounts currently labeled.
+    pub fn labeled(&self) -> Vec<Did> {
+        self.server.labeled(&self.val)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures_util::{StreamExt, TryStreamExt};
+    use k256::ecdsa::{signature::Verifier, Signature, SigningKey};
+    use tokio::net::TcpListener;
+
+    use super::{encode, to_cbor, LabelOutput, LabelServer};
+    use crate::labeler::{query_labels, subscribe_labels};
+
+    #[tokio::test]
+    async fn test_serve_labels() {
+        let labeler = "did:plc:llllllllllllllllllllllll".parse().unwrap();
+        let a = "did:plc:aaaaaaaaaaaaaaaaaaaaaaaa".parse().unwrap();
+        let b = "did:plc:bbbbbbbbbbbbbbbbbbbbbbbb".parse().unwrap();
+        let key = SigningKey::from_slice(&[7; 32]).unwrap();
+        let log = std::env::temp_dir().join(format!("labels-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&log);
+
+        let server = Arc::new(LabelServer::open(labeler, key.clone(), &log).unwrap());
+        let output = LabelOutput::new(server.clone(), "follows-x".to_string());
+        output.label(&a).unwrap();
+        output.label(&b).unwrap();
+        // already labeled
+        output.label(&a).unwrap();
+        output.negate(&a).unwrap();
+        assert_eq!(output.labeled(), vec![b.clone()]);
+
+        // reloading gives the same labels, correctly signed
+        let reloaded = LabelServer::open(server.labeler().clone(), key.clone(), &log).unwrap();
+        let emitted = reloaded.log.lock().unwrap().since(0);
+        assert_eq!(emitted.len(), 3);
+        for e in &emitted {
+            let sig = Signature::from_slice(&e.sig().unwrap()).unwrap();
+            let bytes = to_cbor(&encode(&e.label, None));
+            assert!(key.verifying_key().verify(&bytes, &sig).is_ok());
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        tokio::spawn(server.clone().serve(listener));
+
+        let labels: Vec<_> = subscribe_labels(&endpoint, Some(1))
+            .await
+            .unwrap()
+            .take(2)
+            .collect()
+            .await;
+        let seen: Vec<_> = labels
+            .iter()
+            .map(|(l, seq)| (l.uri.clone(), l.is_negation(), *seq))
+            .collect();
+        assert_eq!(
+            seen,
+            vec![(b.to_string(), false, 2), (a.to_string(), true, 3)]
+        );
+
+        // negated labels aren't applied anymore
+        let applied: Vec<_> = query_labels(&endpoint, server.labeler(), None)
+            .map_ok(|(l, _)| l.uri)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(applied, vec![b.to_string()]);
+
+        std::fs::remove_file(&log).unwrap();
+    }
+}
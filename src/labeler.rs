@@ -0,0 +1,421 @@
+//! from labeler
+//!
+//! Labelers publish their labels on `com.atproto.label.subscribeLabels`, a
+//! websocket of DAG-CBOR frames, and on `com.atproto.label.queryLabels`, both
+//! served from their own endpoint rather than the appview. Only labels on
+//! accounts, whose uri is a did, are used: [`Labels`] tells when an account
+//! gets a wanted label, and when its last one is negated or expires.
+
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    io::Cursor,
+};
+
+use async_stream::stream;
+use atrium_api::types::string::Did;
+use chrono::{DateTime, Utc};
+use futures_core::Stream;
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tracing::{info, warn};
+use url::Url;
+
+use crate::{followers::PageError, threshold::Change};
+
+const PLC_DIRECTORY: &str = "https://plc.directory/";
+
+/// A label, as sent by labelers: signatures and record cids aren't kept.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Label {
+    pub src: Did,
+    pub uri: String,
+    pub val: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub neg: Option<bool>,
+    pub cts: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<String>,
+}
+
+impl Label {
+    /// The labeled account, None for labels on records.
+    pub fn account(&self) -> Option<Did> {
+        self.uri.parse().ok()
+    }
+
+    pub fn is_negation(&self) -> bool {
+        self.neg.unwrap_or(false)
+    }
+
+    /// When the label stops applying, None if it never expires.
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        let exp = DateTime::parse_from_rfc3339(self.exp.as_deref()?).ok()?;
+        Some(exp.to_utc())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct Header {
+    op: i64,
+    #[serde(default)]
+    t: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct LabelsBody {
+    seq: i64,
+    labels: Vec<Label>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ErrorBody {
+    error: String,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct QueryOutput {
+    #[serde(default)]
+    cursor: Option<String>,
+    labels: Vec<Label>,
+}
+
+/// Decodes a `subscribeLabels` frame: a header then a body, both DAG-CBOR.
+///
+/// Gives the labels and their sequence number, None for info frames, and
+/// an error for error frames.
+pub fn decode_frame(frame: &[u8]) -> Result<Option<(i64, Vec<Label>)>, String> {
+    let mut reader = Cursor::new(frame);
+    let header: Header = ciborium::from_reader(&mut reader).map_err(|e| e.to_string())?;
+    match (header.op, header.t.as_deref()) {
+        (1, Some("#labels")) => {
+            let body: LabelsBody = ciborium::from_reader(&mut reader).map_err(|e| e.to_string())?;
+            Ok(Some((body.seq, body.labels)))
+        }
+        (-1, _) => {
+            let body: ErrorBody = ciborium::from_reader(&mut reader).map_err(|e| e.to_string())?;
+            Err(format!(
+                "{}: {}",
+                body.error,
+                body.message.unwrap_or_default()
+            ))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Labels sent from now on, or since `cursor`, along with their sequence number.
+///
+/// Ends when the connection closes or on an error frame.
+pub async fn subscribe_labels(
+    endpoint: &Url,
+    cursor: Option<i64>,
+) -> Result<impl Stream<Item = (Label, i64)>, Box<dyn Error>> {
+    let mut url = endpoint.join("xrpc/com.atproto.label.subscribeLabels")?;
+    if url.scheme() == "https" {
+        url.set_scheme("wss").map_err(|_| "invalid endpoint")?;
+    } else if url.scheme() == "http" {
+        url.set_scheme("ws").map_err(|_| "invalid endpoint")?;
+    }
+    if let Some(cursor) = cursor {
+        url.query_pairs_mut()
+            .append_pair("cursor", &cursor.to_string());
+    }
+    info!(msg = "opening label stream", url = url.as_str());
+    let (frames, _) = connect_async(url.as_str()).await?;
+
+    Ok(stream! {
+        for await frame in frames {
+            let frame = match frame {
+                Ok(Message::Binary(frame)) => frame,
+                Ok(Message::Close(_)) => break,
+                Ok(_) => continue,
+                Err(e) => {
+                    warn!(msg = "label stream error", err = %e);
+                    break;
+                }
+            };
+            match decode_frame(&frame) {
+                Ok(Some((seq, labels))) => {
+                    for label in labels {
+                        yield (label, seq);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!(msg = "label stream error frame", err = e);
+                    break;
+                }
+            }
+        }
+    })
+}
+
+/// Labels on accounts by `labeler`, with the cursor of the next page.
+///
+/// Ends with the error of the first page that can't be read.
+pub fn query_labels<'a>(
+    endpoint: &'a Url,
+    labeler: &'a Did,
+    cursor: Option<String>,
+) -> impl Stream<Item = Result<(Label, Option<String>), PageError>> + 'a {
+    let client = reqwest::Client::new();
+    let get_batch = move |cursor: Option<String>| {
+        let client = client.clone();
+        async move {
+            let mut url = endpoint.join("xrpc/com.atproto.label.queryLabels")?;
+            {
+                let mut query = url.query_pairs_mut();
+                query.append_pair("uriPatterns", "did:*");
+                query.append_pair("sources", labeler.as_str());
+                query.append_pair("limit", "250");
+                if let Some(cursor) = &cursor {
+                    query.append_pair("cursor", cursor);
+                }
+            }
+            let text = client
+                .get(url)
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            Ok::<QueryOutput, Box<dyn Error>>(serde_json::from_str(&text)?)
+        }
+    };
+
+    stream! {
+        let mut cursor = cursor;
+        for i in 0.. {
+            let batch = match get_batch(cursor).await.map_err(|e| PageError(e.to_string())) {
+                Ok(batch) => batch,
+                Err(e) => {
+                    yield Err(e);
+                    break;
+                }
+            };
+            info!(msg = "getting batch", nb = i, cursor = ?batch.cursor);
+            cursor = batch.cursor.clone();
+            info!(msg = "got labels", nb = batch.labels.len());
+            if batch.labels.is_empty() {
+                break;
+            }
+            for label in batch.labels {
+                yield Ok((label, cursor.clone()));
+            }
+            if cursor.is_none() {
+                break;
+            }
+        }
+    }
+}
+
+/// Finds the labeler endpoint in the did document of a labeler.
+pub async fn resolve_endpoint(labeler: &Did) -> Result<Url, Box<dyn Error>> {
+    let url = match labeler.as_str().strip_prefix("did:web:") {
+        Some(host) => format!("https://{host}/.well-known/did.json"),
+        None => format!("{PLC_DIRECTORY}{}", labeler.as_str()),
+    };
+    let doc: serde_json::Value =
+        serde_json::from_str(&reqwest::get(url).await?.error_for_status()?.text().await?)?;
+    let endpoint = doc["service"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|s| {
+            s["id"]
+                .as_str()
+                .is_some_and(|id| id.ends_with("#atproto_labeler"))
+        })
+        .and_then(|s| s["serviceEndpoint"].as_str())
+        .ok_or(format!("not a labeler: {}", labeler.as_str()))?;
+    Ok(endpoint.parse()?)
+}
+
+/// Wanted labels currently applied by a labeler, per account.
+#[derive(Debug)]
+pub struct Labels {
+    labeler: Did,
+    /// any value when empty
+    values: HashSet<String>,
+    /// values per account, with their expiry
+    applied: HashMap<Did, HashMap<String, Option<DateTime<Utc>>>>,
+}
+
+impl Labels {
+    pub fn new(labeler: Did, values: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            labeler,
+            values: values.into_iter().collect(),
+            applied: HashMap::new(),
+        }
+    }
+
+    /// Applies a label, telling whether the account should be added or removed.
+    ///
+    /// An account is added with its first wanted label, and removed once the
+    /// last one is negated or expired. Labels by others, on records or unwanted, give nothing.
+    pub fn apply(&mut self, label: &Label) -> Option<(Did, Change)> {
+        self.apply_at(label, Utc::now())
+    }
+
+    fn apply_at(&mut self, label: &Label, now: DateTime<Utc>) -> Option<(Did, Change)> {
+        if label.src != self.labeler
+            || !(self.values.is_empty() || self.values.contains(&label.val))
+        {
+            return None;
+        }
+        let did = label.account()?;
+        let expires_at = label.expires_at();
+        // an expired label no longer applies, as if negated
+        match label.is_negation() || expires_at.is_some_and(|exp| exp <= now) {
+            false => {
+                let values = self.applied.entry(did.clone()).or_default();
+                let first = values.is_empty();
+                values.insert(label.val.clone(), expires_at);
+                first.then_some((did, Change::Add))
+            }
+            true => self.remove(&did, &label.val),
+        }
+    }
+
+    fn remove(&mut self, did: &Did, val: &str) -> Option<(Did, Change)> {
+        let values = self.applied.get_mut(did)?;
+        values.remove(val)?;
+        if !values.is_empty() {
+            return None;
+        }
+        self.applied.remove(did);
+        Some((did.clone(), Change::Remove))
+    }
+
+    /// Drops the labels expired by `now`, telling which accounts lost their last one.
+    pub fn expire(&mut self, now: DateTime<Utc>) -> Vec<(Did, Change)> {
+        let expired: Vec<(Did, String)> = self
+            .applied
+            .iter()
+            .flat_map(|(did, values)| {
+                values
+                    .iter()
+                    .filter(|(_, exp)| exp.is_some_and(|exp| exp <= now))
+                    .map(|(val, _)| (did.clone(), val.clone()))
+            })
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|(did, val)| self.remove(&did, &val))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::json;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::{accept_async, tungstenite::Message};
+
+    use super::{subscribe_labels, Label, Labels};
+    use crate::threshold::Change;
+
+    fn frame(header: serde_json::Value, body: serde_json::Value) -> Vec<u8> {
+        let mut frame = vec![];
+        ciborium::into_writer(&header, &mut frame).unwrap();
+        ciborium::into_writer(&body, &mut frame).unwrap();
+        frame
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_labels() {
+        let labeler = "did:plc:llllllllllllllllllllllll";
+        let account = "did:plc:aaaaaaaaaaaaaaaaaaaaaaaa";
+        let label = |val: &str, neg: bool| {
+            json!({"src": labeler, "uri": account, "val": val, "neg": neg,
+                   "cts": "2024-11-01T00:00:00.000Z", "sig": serde_json::Value::Null})
+        };
+        let frames = vec![
+            frame(
+                json!({"op": 1, "t": "#labels"}),
+                json!({"seq": 1, "labels": [label("spam", false), label("rude", false)]}),
+            ),
+            frame(
+                json!({"op": 1, "t": "#info"}),
+                json!({"name": "OutdatedCursor"}),
+            ),
+            frame(
+                json!({"op": 1, "t": "#labels"}),
+                json!({"seq": 2, "labels": [label("other", false), label("spam", true)]}),
+            ),
+            frame(
+                json!({"op": 1, "t": "#labels"}),
+                json!({"seq": 3, "labels": [label("rude", true)]}),
+            ),
+            frame(json!({"op": -1}), json!({"error": "FutureCursor"})),
+        ];
+
+        // stands in for the labeler
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(socket).await.unwrap();
+            for f in frames {
+                ws.send(Message::binary(f)).await.unwrap();
+            }
+        });
+
+        let labels: Vec<_> = subscribe_labels(&endpoint.parse().unwrap(), Some(0))
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        let seqs: Vec<i64> = labels.iter().map(|(_, seq)| *seq).collect();
+        assert_eq!(seqs, vec![1, 1, 2, 2, 3]);
+
+        let mut applied = Labels::new(
+            labeler.parse().unwrap(),
+            ["spam".to_string(), "rude".to_string()],
+        );
+        let changes: Vec<_> = labels
+            .iter()
+            .filter_map(|(l, _)| applied.apply(l))
+            .map(|(did, change)| (did.as_str().to_string(), change))
+            .collect();
+        // removed once both labels are negated
+        assert_eq!(
+            changes,
+            vec![
+                (account.to_string(), Change::Add),
+                (account.to_string(), Change::Remove)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expired_labels() {
+        let labeler = "did:plc:llllllllllllllllllllllll";
+        let account = "did:plc:aaaaaaaaaaaaaaaaaaaaaaaa";
+        let label = |exp: &str| Label {
+            src: labeler.parse().unwrap(),
+            uri: account.to_string(),
+            val: "spam".to_string(),
+            neg: None,
+            cts: "2024-11-01T00:00:00.000Z".to_string(),
+            exp: Some(exp.to_string()),
+        };
+        let now = "2024-12-01T00:00:00Z".parse().unwrap();
+        let mut applied = Labels::new(labeler.parse().unwrap(), []);
+
+        // already expired, as if negated
+        assert_eq!(applied.apply_at(&label("2024-11-15T00:00:00Z"), now), None);
+        let (_, change) = applied
+            .apply_at(&label("2024-12-15T00:00:00Z"), now)
+            .unwrap();
+        assert_eq!(change, Change::Add);
+        assert!(applied.expire(now).is_empty());
+        let later = "2025-01-01T00:00:00Z".parse().unwrap();
+        assert_eq!(applied.expire(later)[0].1, Change::Remove);
+    }
+}
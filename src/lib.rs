@@ -1,6 +1,22 @@
-mod feed_generator;
+pub mod dedup;
+pub mod exempt;
+pub mod feed_generator;
 pub mod followers;
-pub mod modlist;
+pub mod graph;
+pub mod label_server;
+pub mod labeler;
+pub mod list;
+pub mod mirror;
+pub mod output;
+pub mod pileon;
+pub mod profile_filter;
+pub mod progress;
 pub mod ratelimit;
+pub mod resolver;
+pub mod search;
+pub mod session;
+pub mod starter_pack;
 pub mod state;
 pub mod subwatch;
+pub mod threshold;
+pub mod writer;
@@ -0,0 +1,520 @@
+//! Lists we write to
+//!
+//! List items work the same for any `app.bsky.graph.list`: moderation lists
+//! (muted or blocked by subscribers), curation lists (e.g. for feeds) and
+//! reference lists (e.g. of starter packs). Each use opens a list with the
+//! [`Purpose`]s it expects, see [`List::open`].
+
+use async_stream::stream;
+use clap::ValueEnum;
+use futures_util::{pin_mut, stream::StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{error::Error, fmt};
+use tracing::{info, warn};
+
+use atrium_api::{
+    agent::store::SessionStore,
+    app::bsky::{
+        actor::defs::ProfileViewData,
+        graph::{
+            defs::{ListViewData, CURATELIST, MODLIST, REFERENCELIST},
+            get_list, list, listitem, Listitem,
+        },
+    },
+    record::KnownRecord,
+    types::{
+        string::{Datetime, Did},
+        Collection, LimitedNonZeroU8,
+    },
+    xrpc::XrpcClient,
+};
+use bsky_sdk::BskyAgent;
+use futures_core::Stream;
+use ipld_core::ipld::Ipld;
+
+use crate::followers::PageError;
+use crate::output;
+use crate::resolver;
+
+/// What a list is for, see `app.bsky.graph.defs#listPurpose`.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Purpose {
+    /// moderation list, muted or blocked by its subscribers
+    Mod,
+    /// curation list, e.g. for feeds
+    Curate,
+    /// reference list, e.g. of a starter pack
+    Reference,
+}
+
+impl Purpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Purpose::Mod => MODLIST,
+            Purpose::Curate => CURATELIST,
+            Purpose::Reference => REFERENCELIST,
+        }
+    }
+
+    /// None for purposes we don't know of.
+    pub fn parse(purpose: &str) -> Option<Self> {
+        [Purpose::Mod, Purpose::Curate, Purpose::Reference]
+            .into_iter()
+            .find(|p| p.as_str() == purpose)
+    }
+}
+
+impl fmt::Display for Purpose {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct List(String);
+
+/// A list checked by [`List::open`]: it exists, has the expected purpose
+/// and belongs to the logged-in account.
+#[derive(Debug)]
+pub struct ListHandle {
+    list: List,
+    name: String,
+    purpose: Purpose,
+    nb_members: usize,
+}
+
+impl ListHandle {
+    pub fn list(&self) -> &List {
+        &self.list
+    }
+
+    pub fn into_list(self) -> List {
+        self.list
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn purpose(&self) -> Purpose {
+        self.purpose
+    }
+
+    pub fn nb_members(&self) -> usize {
+        self.nb_members
+    }
+}
+
+impl List {
+    pub fn new(list: String) -> Self {
+        Self(list)
+    }
+
+    /// Same as [`List::new`] but also accepts bsky.app links and handle-based AT-URIs.
+    pub async fn resolve<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        agent: &BskyAgent<T, S>,
+        list: &str,
+    ) -> Result<Self, Box<dyn Error>> {
+        Ok(Self(resolver::resolve_list(agent, list).await?))
+    }
+
+    /// Resolves and checks a list we can write to, with the expected purpose.
+    pub async fn open<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        agent: &BskyAgent<T, S>,
+        list: &str,
+        purpose: Purpose,
+    ) -> Result<ListHandle, Box<dyn Error>> {
+        Self::open_as(agent, list, &[purpose]).await
+    }
+
+    /// Same as [`List::open`], accepting any of the provided purposes.
+    pub async fn open_as<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        agent: &BskyAgent<T, S>,
+        list: &str,
+        purposes: &[Purpose],
+    ) -> Result<ListHandle, Box<dyn Error>> {
+        let list = Self::resolve(agent, list).await?;
+        let view = Self::get_view(list.0.clone(), agent)
+            .await
+            .map_err(|e| format!("could not get list {}: {e}", list.0))?;
+
+        let Some(purpose) = Purpose::parse(&view.purpose).filter(|p| purposes.contains(p)) else {
+            let expected: Vec<&str> = purposes.iter().map(Purpose::as_str).collect();
+            return Err(format!(
+                "list {} has purpose {}, expected one of {expected:?}",
+                list.0, view.purpose
+            )
+            .into());
+        };
+
+        let session = agent.get_session().await.ok_or("not logged in")?;
+        if view.creator.did != session.data.did {
+            return Err(format!(
+                "list {} belongs to {}, not to logged-in {}",
+                list.0,
+                view.creator.did.as_str(),
+                session.data.did.as_str()
+            )
+            .into());
+        }
+
+        info!(msg = "opened list", list = list.0, name = view.name, purpose = %purpose, nb_members = ?view.list_item_count);
+        Ok(ListHandle {
+            list,
+            name: view.name,
+            purpose,
+            nb_members: view.list_item_count.unwrap_or_default(),
+        })
+    }
+
+    /// AT-URI of the list
+    pub fn uri(&self) -> &str {
+        &self.0
+    }
+
+    /// Creates a new list in the agent's repo.
+    pub async fn create<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        agent: &BskyAgent<T, S>,
+        name: String,
+        description: Option<String>,
+        purpose: Purpose,
+    ) -> Result<Self, Box<dyn Error>> {
+        let created = agent
+            .create_record(list::Record {
+                data: list::RecordData {
+                    avatar: None,
+                    created_at: Datetime::now(),
+                    description,
+                    description_facets: None,
+                    labels: None,
+                    name,
+                    purpose: purpose.as_str().to_string(),
+                },
+                extra_data: Ipld::Null,
+            })
+            .await?;
+        info!(msg = "created list", uri = created.uri);
+        Ok(Self(created.data.uri))
+    }
+
+    /// add did to list
+    pub async fn add<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        did: Did,
+    ) -> Result<(), Box<dyn Error>> {
+        agent
+            .create_record(listitem::Record {
+                data: listitem::RecordData {
+                    created_at: Datetime::now(),
+                    list: self.0.clone(),
+                    subject: did,
+                },
+                extra_data: Ipld::Null,
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// add did to list, unless it's already in it.
+    /// Goes through the whole list: meant for retries of [`List::add`].
+    pub async fn add_missing<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        did: Did,
+    ) -> Result<(), Box<dyn Error>> {
+        if !self.items_of(agent, &did).await?.is_empty() {
+            info!(msg = "already in list", list = self.0, did = ?did);
+            return Ok(());
+        }
+        self.add(agent, did).await
+    }
+
+    /// remove did from list, deleting every list item pointing to it.
+    /// Goes through the whole list: meant for occasional removals.
+    pub async fn remove<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        did: &Did,
+    ) -> Result<(), Box<dyn Error>> {
+        let uris = self.items_of(agent, did).await?;
+        for uri in uris {
+            agent.delete_record(&uri).await?;
+            info!(msg = "removed from list", list = self.0, did = ?did);
+        }
+        Ok(())
+    }
+
+    /// uris of the list items pointing to did.
+    async fn items_of<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        did: &Did,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut uris = vec![];
+        let mut cursor = None;
+        loop {
+            let batch = agent
+                .api
+                .app
+                .bsky
+                .graph
+                .get_list(get_list::Parameters {
+                    data: get_list::ParametersData {
+                        cursor,
+                        limit: Some(LimitedNonZeroU8::MAX),
+                        list: self.0.clone(),
+                    },
+                    extra_data: Ipld::Null,
+                })
+                .await?;
+            uris.extend(
+                batch
+                    .data
+                    .items
+                    .into_iter()
+                    .filter(|i| i.subject.did == *did)
+                    .map(|i| i.data.uri),
+            );
+            cursor = batch.data.cursor;
+            if cursor.is_none() {
+                return Ok(uris);
+            }
+        }
+    }
+
+    /// add dids to list, writing up to [`output::MAX_WRITES`] records per request
+    pub async fn add_batch<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        dids: Vec<Did>,
+    ) -> Result<(), Box<dyn Error>> {
+        let records = dids
+            .into_iter()
+            .map(|did| {
+                KnownRecord::from(listitem::Record {
+                    data: listitem::RecordData {
+                        created_at: Datetime::now(),
+                        list: self.0.clone(),
+                        subject: did,
+                    },
+                    extra_data: Ipld::Null,
+                })
+            })
+            .collect();
+        output::create_batch(agent, Listitem::nsid(), records).await
+    }
+
+    /// Consume a stream of dids, adding each of them into the list
+    pub async fn add_stream<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        dids: impl Stream<Item = Result<(Did, Option<String>), PageError>>,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        self.add_stream_checkpointed(agent, dids, |_| Ok(())).await
+    }
+
+    /// Consume a stream of dids, adding each of them into the list
+    /// Calls `checkpoint` with the cursor to resume from each time a page has been fully written.
+    ///
+    /// All items of a page carry the cursor of the next page, so a page is done
+    /// once the cursor changes, or once the stream ends without an error.
+    pub async fn add_stream_checkpointed<
+        T: XrpcClient + Send + Sync,
+        S: SessionStore + Send + Sync,
+    >(
+        &self,
+        agent: &BskyAgent<T, S>,
+        dids: impl Stream<Item = Result<(Did, Option<String>), PageError>>,
+        mut checkpoint: impl FnMut(&str) -> Result<(), Box<dyn Error>>,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        pin_mut!(dids);
+        let mut last_cursor: Option<String> = None;
+        while let Some(item) = dids.next().await {
+            // the current page may be partly written, it's read again on resume
+            let (did, cursor) = item?;
+            if let Some(c) = cursor {
+                if let Some(done) = last_cursor.as_deref().filter(|l| *l != c) {
+                    checkpoint(done)?;
+                }
+                last_cursor = Some(c);
+            }
+            // info!(msg = "adding to list", list = self.0, did = ?did);
+            self.add(agent, did).await?;
+        }
+
+        match &last_cursor {
+            Some(c) => checkpoint(c)?,
+            None => warn!(msg = "no last cursor found!"),
+        }
+
+        Ok(last_cursor)
+    }
+
+    /// gets members of provided list, until the first page that can't be read.
+    /// set cursor to a cursor if you want to skip a part of the list.
+    pub async fn get_members<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        list: String,
+        agent: &BskyAgent<T, S>,
+        cursor: Option<String>,
+    ) -> impl Stream<Item = Result<ProfileViewData, PageError>> + '_ {
+        let get_batch = |list: String, cursor: Option<String>| async {
+            agent
+                .api
+                .app
+                .bsky
+                .graph
+                .get_list(get_list::Parameters {
+                    data: get_list::ParametersData {
+                        cursor,
+                        limit: None,
+                        list,
+                    },
+                    extra_data: Ipld::Null,
+                })
+                .await
+        };
+
+        stream! {
+            let mut cursor = cursor;
+            for i in 0.. {
+                let batch = match get_batch(list.clone(), cursor).await {
+                    Ok(batch) => batch,
+                    Err(e) => {
+                        yield Err(PageError(e.to_string()));
+                        break;
+                    }
+                };
+                info!(msg="getting batch", nb=i, cursor=?batch.cursor);
+                cursor = batch.cursor.clone();
+                info!(msg="got members", nb=&batch.data.items.len());
+                for member in batch.data.items {
+                    yield Ok(member.data.subject.data);
+                }
+
+                if cursor.is_none() {
+                    break;
+                }
+            }
+        }
+    }
+
+    pub async fn get_last_member<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        list: String,
+        agent: &BskyAgent<T, S>,
+    ) -> Option<ProfileViewData> {
+        let stream = Self::get_members(list, agent, None).await;
+        pin_mut!(stream);
+        stream.next().await.and_then(Result::ok)
+    }
+
+    /// gets name, purpose and description of provided list.
+    pub async fn get_view<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        list: String,
+        agent: &BskyAgent<T, S>,
+    ) -> Result<ListViewData, Box<dyn Error>> {
+        let view = agent
+            .api
+            .app
+            .bsky
+            .graph
+            .get_list(get_list::Parameters {
+                data: get_list::ParametersData {
+                    cursor: None,
+                    limit: None,
+                    list,
+                },
+                extra_data: Ipld::Null,
+            })
+            .await?
+            .data
+            .list
+            .data;
+        Ok(view)
+    }
+
+    pub async fn get_nb_members<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        list: String,
+        agent: &BskyAgent<T, S>,
+    ) -> Option<usize> {
+        let nb = agent
+            .api
+            .app
+            .bsky
+            .graph
+            .get_list(get_list::Parameters {
+                data: get_list::ParametersData {
+                    cursor: None,
+                    limit: None,
+                    list,
+                },
+                extra_data: Ipld::Null,
+            })
+            .await
+            .unwrap()
+            .list
+            .list_item_count;
+
+        nb
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bsky_sdk::agent::{
+        config::{Config, FileStore},
+        BskyAgentBuilder,
+    };
+
+    use crate::{
+        list::{List, Purpose},
+        ratelimit::RateLimited,
+    };
+
+    #[test]
+    fn test_purpose() {
+        for purpose in [Purpose::Mod, Purpose::Curate, Purpose::Reference] {
+            assert_eq!(Purpose::parse(purpose.as_str()), Some(purpose));
+        }
+        assert_eq!(
+            Purpose::parse("app.bsky.graph.defs#curatelist"),
+            Some(Purpose::Curate)
+        );
+        assert_eq!(Purpose::parse("app.bsky.graph.defs#otherlist"), None);
+    }
+
+    #[tokio::test]
+    async fn test_get() {
+        let client = RateLimited::default();
+        let agent = BskyAgentBuilder::default()
+            .config(Config::load(&FileStore::new("config.json")).await.unwrap())
+            .client(client)
+            .build()
+            .await
+            .unwrap();
+
+        let modlist = List::new(
+            "at://did:plc:hhj2b7rqtaffsbd7a52dhf4j/app.bsky.graph.list/3lbd7snb23r2y".into(),
+        );
+
+        let last_member = List::get_last_member(modlist.0, &agent).await;
+        println!("{last_member:#?}");
+    }
+
+    #[tokio::test]
+    async fn test_nb_followers() {
+        let client = RateLimited::default();
+        let agent = BskyAgentBuilder::default()
+            .config(Config::load(&FileStore::new("config.json")).await.unwrap())
+            .client(client)
+            .build()
+            .await
+            .unwrap();
+
+        let modlist = List::new(
+            "at://did:plc:hhj2b7rqtaffsbd7a52dhf4j/app.bsky.graph.list/3lbd7snb23r2y".into(),
+        );
+        let nb_members = List::get_nb_members(modlist.0, &agent).await;
+        println!("{nb_members:?}");
+    }
+}
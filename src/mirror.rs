@@ -0,0 +1,259 @@
+//! Mirror of another list
+//!
+//! Anyone's list can be read, but jetstream only tells which list item record
+//! was deleted, not its subject: [`Mirror`] keeps the record keys of the source
+//! list's items. They're saved to a snapshot file, so that items removed while
+//! the mirror wasn't running are noticed on the next start. Those removals stay
+//! in the snapshot until written, see [`Mirror::removed_written`].
+
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use atrium_api::{
+    agent::store::SessionStore,
+    app::bsky::graph::get_list,
+    types::{string::Did, LimitedNonZeroU8},
+    xrpc::XrpcClient,
+};
+use bsky_sdk::BskyAgent;
+use ipld_core::ipld::Ipld;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::subwatch::ListItemEvent;
+
+/// What to do to the mirroring list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Update {
+    Add(Did),
+    Remove(Did),
+}
+
+/// Items of the source list, by record key.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Snapshot {
+    list: String,
+    items: HashMap<String, Did>,
+    /// members removed while not running, until their removal is written
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    removed: Vec<Did>,
+}
+
+impl Snapshot {
+    fn members(&self) -> HashSet<Did> {
+        self.items.values().cloned().collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct Mirror {
+    path: PathBuf,
+    snapshot: Mutex<Snapshot>,
+}
+
+/// Items of a list by record key, taken from their AT-URI.
+async fn list_items<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+    agent: &BskyAgent<T, S>,
+    list: &str,
+) -> Result<HashMap<String, Did>, Box<dyn Error>> {
+    let mut items = HashMap::new();
+    let mut cursor = None;
+    loop {
+        let batch = agent
+            .api
+            .app
+            .bsky
+            .graph
+            .get_list(get_list::Parameters {
+                data: get_list::ParametersData {
+                    cursor,
+                    limit: Some(LimitedNonZeroU8::MAX),
+                    list: list.to_string(),
+                },
+                extra_data: Ipld::Null,
+            })
+            .await?;
+        for item in batch.data.items {
+            let rkey = item.uri.rsplit('/').next().unwrap_or_default().to_string();
+            items.insert(rkey, item.data.subject.data.did);
+        }
+        cursor = batch.data.cursor;
+        if cursor.is_none() {
+            return Ok(items);
+        }
+    }
+}
+
+impl Mirror {
+    /// Reads the source list, returning its members removed since the previous snapshot,
+    /// or whose removal wasn't written by the previous run.
+    pub async fn load<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        agent: &BskyAgent<T, S>,
+        list: String,
+        path: impl AsRef<Path>,
+    ) -> Result<(Self, Vec<Did>), Box<dyn Error>> {
+        let path = path.as_ref().to_path_buf();
+        let previous: Snapshot = match File::open(&path) {
+            Ok(r) => serde_json::from_reader(r)?,
+            Err(_) => Snapshot::default(),
+        };
+        let items = list_items(agent, &list).await?;
+        let mut snapshot = Snapshot {
+            list,
+            items,
+            removed: vec![],
+        };
+
+        // a snapshot of another list says nothing about this one
+        if previous.list == snapshot.list {
+            let members = snapshot.members();
+            let mut removed: HashSet<Did> = previous.members();
+            removed.extend(previous.removed);
+            snapshot.removed = removed.difference(&members).cloned().collect();
+        }
+        let removed = snapshot.removed.clone();
+        info!(
+            msg = "loaded source list",
+            list = snapshot.list,
+            nb = snapshot.items.len(),
+            removed = removed.len()
+        );
+        let mirror = Self {
+            path,
+            snapshot: Mutex::new(snapshot),
+        };
+        mirror.save()?;
+        Ok((mirror, removed))
+    }
+
+    pub fn list(&self) -> String {
+        self.snapshot.lock().unwrap().list.clone()
+    }
+
+    pub fn members(&self) -> HashSet<Did> {
+        self.snapshot.lock().unwrap().members()
+    }
+
+    /// Forgets the removals returned by [`Mirror::load`], once they're all written.
+    pub fn removed_written(&self) {
+        self.snapshot.lock().unwrap().removed.clear();
+    }
+
+    /// What a list item event of the source list's owner changes, without applying it:
+    /// removals are only applied once written, see [`Mirror::apply`].
+    ///
+    /// Events already known, or on other lists, give nothing. A did is only
+    /// removed once no item of the source list points to it anymore.
+    pub fn update(&self, event: &ListItemEvent) -> Option<Update> {
+        let snapshot = self.snapshot.lock().unwrap();
+        match event {
+            ListItemEvent::Added {
+                list,
+                subject,
+                rkey,
+            } => {
+                if *list != snapshot.list || snapshot.items.contains_key(rkey) {
+                    return None;
+                }
+                Some(Update::Add(subject.clone()))
+            }
+            ListItemEvent::Removed { rkey } => {
+                let subject = snapshot.items.get(rkey)?;
+                let listed = snapshot
+                    .items
+                    .iter()
+                    .any(|(other, did)| other != rkey && did == subject);
+                match listed {
+                    true => None,
+                    false => Some(Update::Remove(subject.clone())),
+                }
+            }
+        }
+    }
+
+    /// Applies a list item event of the source list's owner, returning its [`Mirror::update`].
+    pub fn apply(&self, event: ListItemEvent) -> Option<Update> {
+        let update = self.update(&event);
+        let mut snapshot = self.snapshot.lock().unwrap();
+        match event {
+            ListItemEvent::Added {
+                list,
+                subject,
+                rkey,
+            } if list == snapshot.list => {
+                snapshot.items.insert(rkey, subject);
+            }
+            ListItemEvent::Added { .. } => {}
+            ListItemEvent::Removed { rkey } => {
+                snapshot.items.remove(&rkey);
+            }
+        }
+        update
+    }
+
+    /// Writes the snapshot to a temporary file then moves it over the previous one.
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let tmp = self.path.with_extension("tmp");
+        {
+            let snapshot = self.snapshot.lock().unwrap();
+            serde_json::to_writer(File::create(&tmp)?, &*snapshot)?;
+        }
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::PathBuf, sync::Mutex};
+
+    use atrium_api::types::string::Did;
+
+    use super::{Mirror, Snapshot, Update};
+    use crate::subwatch::ListItemEvent;
+
+    #[test]
+    fn test_apply() {
+        let list = "at://did:plc:aaaaaaaaaaaaaaaaaaaaaaaa/app.bsky.graph.list/3kl".to_string();
+        let did: Did = "did:plc:bbbbbbbbbbbbbbbbbbbbbbbb".parse().unwrap();
+        let mirror = Mirror {
+            path: PathBuf::new(),
+            snapshot: Mutex::new(Snapshot {
+                list: list.clone(),
+                items: HashMap::from([("1".to_string(), did.clone())]),
+                removed: vec![],
+            }),
+        };
+        let added = |list: &str, rkey: &str| ListItemEvent::Added {
+            list: list.to_string(),
+            subject: did.clone(),
+            rkey: rkey.to_string(),
+        };
+        let removed = |rkey: &str| ListItemEvent::Removed {
+            rkey: rkey.to_string(),
+        };
+
+        assert_eq!(mirror.apply(added(&list, "1")), None);
+        assert_eq!(
+            mirror.update(&removed("1")),
+            Some(Update::Remove(did.clone()))
+        );
+        assert_eq!(mirror.apply(added("at://other", "2")), None);
+        // same did listed twice, only removed with its last item
+        assert_eq!(
+            mirror.apply(added(&list, "2")),
+            Some(Update::Add(did.clone()))
+        );
+        assert_eq!(mirror.apply(removed("1")), None);
+        assert_eq!(
+            mirror.apply(removed("2")),
+            Some(Update::Remove(did.clone()))
+        );
+        assert_eq!(mirror.apply(removed("3")), None);
+    }
+}
@@ -0,0 +1,531 @@
+//! Where written dids end up
+//!
+//! A shared modlist isn't for everyone: some want to act on their own account
+//! only. [`Output`] is implemented by [`List`] (list items), [`Blocks`]
+//! (`app.bsky.graph.block` records), [`Mutes`] (`app.bsky.graph.muteActor`)
+//! and [`LabelOutput`]. Options and pairs of outputs are outputs too, so that
+//! a source can write to several of them.
+//!
+//! List items aren't idempotent: sources skip dids already written through a
+//! [`SeenSet`], and writes are retried with [`Output::add_again`], which skips
+//! dids already in the list. When writing to several outputs, [`Deduped`] skips
+//! them per output, and sources only those written to all of them.
+//! Blocks skip accounts already blocked.
+
+use std::{collections::HashMap, error::Error, future::Future, sync::Mutex};
+
+use atrium_api::{
+    agent::store::SessionStore,
+    app::bsky::graph::{block, get_mutes, mute_actor, unmute_actor},
+    com::atproto::repo::{apply_writes, list_records},
+    record::KnownRecord,
+    types::{
+        string::{AtIdentifier, Datetime, Did, Nsid},
+        Collection, LimitedNonZeroU8, TryFromUnknown, TryIntoUnknown,
+    },
+    xrpc::XrpcClient,
+};
+use bsky_sdk::BskyAgent;
+use ipld_core::ipld::Ipld;
+use tracing::info;
+
+use crate::{dedup::SeenSet, label_server::LabelOutput, list::List};
+
+/// Max number of writes accepted by a single `applyWrites` call.
+pub const MAX_WRITES: usize = 200;
+
+pub trait Output: Sync {
+    /// Writes a did, e.g. adds it to a list.
+    fn add<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        did: Did,
+    ) -> impl Future<Output = Result<(), Box<dyn Error>>> + Send;
+
+    /// Writes a did again after a failed write, which may have partly succeeded.
+    fn add_again<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        did: Did,
+    ) -> impl Future<Output = Result<(), Box<dyn Error>>> + Send {
+        self.add(agent, did)
+    }
+
+    /// Undoes every write of a did.
+    fn remove<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        did: &Did,
+    ) -> impl Future<Output = Result<(), Box<dyn Error>>> + Send;
+
+    /// Writes dids, in as few requests as possible.
+    fn add_batch<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        dids: Vec<Did>,
+    ) -> impl Future<Output = Result<(), Box<dyn Error>>> + Send {
+        async move {
+            for did in dids {
+                self.add(agent, did).await?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Creates records in the logged-in account's repo, up to [`MAX_WRITES`] per request.
+pub(crate) async fn create_batch<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+    agent: &BskyAgent<T, S>,
+    collection: Nsid,
+    records: Vec<KnownRecord>,
+) -> Result<(), Box<dyn Error>> {
+    let repo = agent.get_session().await.ok_or("not logged in")?.data.did;
+    for chunk in records.chunks(MAX_WRITES) {
+        let writes = chunk
+            .iter()
+            .map(|record| {
+                Ok(apply_writes::InputWritesItem::Create(Box::new(
+                    apply_writes::CreateData {
+                        collection: collection.clone(),
+                        rkey: None,
+                        value: record.clone().try_into_unknown()?,
+                    }
+                    .into(),
+                )))
+            })
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+
+        agent
+            .api
+            .com
+            .atproto
+            .repo
+            .apply_writes(
+                apply_writes::InputData {
+                    repo: AtIdentifier::Did(repo.clone()),
+                    swap_commit: None,
+                    validate: None,
+                    writes,
+                }
+                .into(),
+            )
+            .await?;
+        info!(
+            msg = "added batch",
+            collection = collection.as_str(),
+            nb = chunk.len()
+        );
+    }
+    Ok(())
+}
+
+impl Output for List {
+    async fn add<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        did: Did,
+    ) -> Result<(), Box<dyn Error>> {
+        List::add(self, agent, did).await
+    }
+
+    async fn add_again<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        did: Did,
+    ) -> Result<(), Box<dyn Error>> {
+        List::add_missing(self, agent, did).await
+    }
+
+    async fn remove<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        did: &Did,
+    ) -> Result<(), Box<dyn Error>> {
+        List::remove(self, agent, did).await
+    }
+
+    async fn add_batch<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        dids: Vec<Did>,
+    ) -> Result<(), Box<dyn Error>> {
+        List::add_batch(self, agent, dids).await
+    }
+}
+
+/// Blocks dids from the logged-in account.
+///
+/// Its block records are read once by [`Blocks::load`] and kept by did:
+/// accounts already blocked, e.g. by hand, are skipped.
+#[derive(Debug, Default)]
+pub struct Blocks(Mutex<HashMap<Did, Vec<String>>>);
+
+fn block_record(did: Did) -> block::Record {
+    block::Record {
+        data: block::RecordData {
+            created_at: Datetime::now(),
+            subject: did,
+        },
+        extra_data: Ipld::Null,
+    }
+}
+
+impl Blocks {
+    /// Reads the block records of the logged-in account.
+    pub async fn load<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        agent: &BskyAgent<T, S>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let repo = agent.get_session().await.ok_or("not logged in")?.data.did;
+        let mut records: HashMap<Did, Vec<String>> = HashMap::new();
+        let mut cursor = None;
+        loop {
+            let batch = agent
+                .api
+                .com
+                .atproto
+                .repo
+                .list_records(
+                    list_records::ParametersData {
+                        collection: atrium_api::app::bsky::graph::Block::nsid(),
+                        cursor,
+                        limit: Some(LimitedNonZeroU8::MAX),
+                        repo: AtIdentifier::Did(repo.clone()),
+                        reverse: None,
+                        rkey_end: None,
+                        rkey_start: None,
+                    }
+                    .into(),
+                )
+                .await?;
+            for record in batch.data.records {
+                let block = block::Record::try_from_unknown(record.data.value)?;
+                records
+                    .entry(block.data.subject)
+                    .or_default()
+                    .push(record.data.uri);
+            }
+            cursor = batch.data.cursor;
+            if cursor.is_none() {
+                info!(msg = "loaded blocks", nb = records.len());
+                return Ok(Self(Mutex::new(records)));
+            }
+        }
+    }
+
+    /// Accounts currently blocked, to skip them.
+    pub fn blocked(&self) -> Vec<Did> {
+        self.0.lock().unwrap().keys().cloned().collect()
+    }
+}
+
+impl Output for Blocks {
+    async fn add<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        did: Did,
+    ) -> Result<(), Box<dyn Error>> {
+        let blocked = self.0.lock().unwrap().contains_key(&did);
+        if blocked {
+            info!(msg = "already blocked", did = ?did);
+            return Ok(());
+        }
+        let record = agent.create_record(block_record(did.clone())).await?;
+        self.0
+            .lock()
+            .unwrap()
+            .entry(did)
+            .or_default()
+            .push(record.data.uri);
+        Ok(())
+    }
+
+    /// Deletes every block record pointing to the did.
+    async fn remove<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        did: &Did,
+    ) -> Result<(), Box<dyn Error>> {
+        let uris = self.0.lock().unwrap().get(did).cloned().unwrap_or_default();
+        for uri in uris {
+            agent.delete_record(&uri).await?;
+            // those not deleted yet are kept, to be deleted on retries
+            if let Some(left) = self.0.lock().unwrap().get_mut(did) {
+                left.retain(|u| *u != uri);
+            }
+            info!(msg = "unblocked", did = ?did);
+        }
+        self.0.lock().unwrap().remove(did);
+        Ok(())
+    }
+}
+
+/// Mutes dids for the logged-in account, privately.
+///
+/// Muting is idempotent, and there's no batch endpoint: batches are muted one by one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Mutes;
+
+impl Mutes {
+    /// Accounts currently muted, to skip them.
+    pub async fn muted<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        agent: &BskyAgent<T, S>,
+    ) -> Result<Vec<Did>, Box<dyn Error>> {
+        let mut muted = vec![];
+        let mut cursor = None;
+        loop {
+            let batch = agent
+                .api
+                .app
+                .bsky
+                .graph
+                .get_mutes(
+                    get_mutes::ParametersData {
+                        cursor,
+                        limit: Some(LimitedNonZeroU8::MAX),
+                    }
+                    .into(),
+                )
+                .await?;
+            muted.extend(batch.data.mutes.into_iter().map(|p| p.data.did));
+            cursor = batch.data.cursor;
+            if cursor.is_none() {
+                info!(msg = "loaded mutes", nb = muted.len());
+                return Ok(muted);
+            }
+        }
+    }
+}
+
+impl Output for Mutes {
+    async fn add<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        did: Did,
+    ) -> Result<(), Box<dyn Error>> {
+        agent
+            .api
+            .app
+            .bsky
+            .graph
+            .mute_actor(
+                mute_actor::InputData {
+                    actor: AtIdentifier::Did(did),
+                }
+                .into(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn remove<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        did: &Did,
+    ) -> Result<(), Box<dyn Error>> {
+        agent
+            .api
+            .app
+            .bsky
+            .graph
+            .unmute_actor(
+                unmute_actor::InputData {
+                    actor: AtIdentifier::Did(did.clone()),
+                }
+                .into(),
+            )
+            .await?;
+        info!(msg = "unmuted", did = ?did);
+        Ok(())
+    }
+}
+
+impl Output for LabelOutput {
+    async fn add<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        _agent: &BskyAgent<T, S>,
+        did: Did,
+    ) -> Result<(), Box<dyn Error>> {
+        self.label(&did)
+    }
+
+    async fn remove<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        _agent: &BskyAgent<T, S>,
+        did: &Did,
+    ) -> Result<(), Box<dyn Error>> {
+        self.negate(did)
+    }
+}
+
+/// Skips dids already written to the output, e.g. list members when blocking them too.
+#[derive(Debug)]
+pub struct Deduped<O> {
+    output: O,
+    seen: SeenSet,
+}
+
+impl<O: Output> Deduped<O> {
+    /// Starts from `seen`, the dids already written to `output`.
+    pub fn new(output: O, seen: SeenSet) -> Self {
+        Self { output, seen }
+    }
+}
+
+impl<O: Output> Output for Deduped<O> {
+    async fn add<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        did: Did,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.seen.contains(&did) {
+            return Ok(());
+        }
+        self.output.add(agent, did.clone()).await?;
+        self.seen.insert(&did);
+        Ok(())
+    }
+
+    async fn add_again<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        did: Did,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.seen.contains(&did) {
+            return Ok(());
+        }
+        self.output.add_again(agent, did.clone()).await?;
+        self.seen.insert(&did);
+        Ok(())
+    }
+
+    async fn remove<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        did: &Did,
+    ) -> Result<(), Box<dyn Error>> {
+        self.output.remove(agent, did).await?;
+        self.seen.remove(did);
+        Ok(())
+    }
+}
+
+impl<O: Output> Output for &O {
+    fn add<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        did: Did,
+    ) -> impl Future<Output = Result<(), Box<dyn Error>>> + Send {
+        (*self).add(agent, did)
+    }
+
+    fn remove<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        did: &Did,
+    ) -> impl Future<Output = Result<(), Box<dyn Error>>> + Send {
+        (*self).remove(agent, did)
+    }
+
+    fn add_again<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        did: Did,
+    ) -> impl Future<Output = Result<(), Box<dyn Error>>> + Send {
+        (*self).add_again(agent, did)
+    }
+
+    fn add_batch<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        dids: Vec<Did>,
+    ) -> impl Future<Output = Result<(), Box<dyn Error>>> + Send {
+        (*self).add_batch(agent, dids)
+    }
+}
+
+/// Writes nothing when None.
+impl<O: Output> Output for Option<O> {
+    async fn add<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        did: Did,
+    ) -> Result<(), Box<dyn Error>> {
+        match self {
+            Some(output) => output.add(agent, did).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn add_again<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        did: Did,
+    ) -> Result<(), Box<dyn Error>> {
+        match self {
+            Some(output) => output.add_again(agent, did).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn remove<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        did: &Did,
+    ) -> Result<(), Box<dyn Error>> {
+        match self {
+            Some(output) => output.remove(agent, did).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn add_batch<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        dids: Vec<Did>,
+    ) -> Result<(), Box<dyn Error>> {
+        match self {
+            Some(output) => output.add_batch(agent, dids).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Writes to both, first one first: a failed write to the second one is
+/// retried on both, with [`Output::add_again`] skipping what was written.
+impl<A: Output, B: Output> Output for (A, B) {
+    async fn add<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        did: Did,
+    ) -> Result<(), Box<dyn Error>> {
+        self.0.add(agent, did.clone()).await?;
+        self.1.add(agent, did).await
+    }
+
+    async fn add_again<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        did: Did,
+    ) -> Result<(), Box<dyn Error>> {
+        self.0.add_again(agent, did.clone()).await?;
+        self.1.add_again(agent, did).await
+    }
+
+    async fn remove<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        did: &Did,
+    ) -> Result<(), Box<dyn Error>> {
+        self.0.remove(agent, did).await?;
+        self.1.remove(agent, did).await
+    }
+
+    async fn add_batch<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        dids: Vec<Did>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.0.add_batch(agent, dids.clone()).await?;
+        self.1.add_batch(agent, dids).await
+    }
+}
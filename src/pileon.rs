@@ -0,0 +1,170 @@
+//! Rate based rules on replies and mentions
+//!
+//! A single reply is no pile-on: authors are only added once they reply to
+//! (or mention) the protected account too often, or take part in a burst of
+//! interactions from new accounts. Times are jetstream's, in microseconds.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    time::Duration,
+};
+
+use atrium_api::types::string::Did;
+
+/// Number of tracked authors above which those without recent interactions are dropped.
+const PRUNE_ABOVE: usize = 10_000;
+
+/// More than `max` interactions from the same account within `window`.
+#[derive(Debug, Clone, Copy)]
+pub struct Repeated {
+    pub max: usize,
+    pub window: Duration,
+}
+
+/// Interactions from more than `max` new accounts within `window`.
+#[derive(Debug, Clone, Copy)]
+pub struct Burst {
+    pub max: usize,
+    pub window: Duration,
+}
+
+/// Why an author gets added: the count that went over the limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Repeated(usize),
+    Burst(usize),
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trigger::Repeated(n) => write!(f, "{n} interactions from the account"),
+            Trigger::Burst(n) => write!(f, "burst of {n} new accounts"),
+        }
+    }
+}
+
+fn micros(window: Duration) -> i64 {
+    window.as_micros() as i64
+}
+
+#[derive(Debug, Default)]
+pub struct Rates {
+    repeated: Option<Repeated>,
+    burst: Option<Burst>,
+    by_author: HashMap<Did, VecDeque<i64>>,
+    new_accounts: VecDeque<(i64, Did)>,
+}
+
+impl Rates {
+    pub fn new(repeated: Option<Repeated>, burst: Option<Burst>) -> Self {
+        Self {
+            repeated,
+            burst,
+            ..Default::default()
+        }
+    }
+
+    /// Whether interactions must tell if their author is a new account.
+    pub fn needs_age(&self) -> bool {
+        self.burst.is_some()
+    }
+
+    /// Records an interaction, returning the authors going over a limit.
+    ///
+    /// Authors of a burst are all returned each time it grows.
+    pub fn record(&mut self, author: &Did, ts: i64, new_account: bool) -> Vec<(Did, Trigger)> {
+        let mut triggered = vec![];
+
+        if let Some(Repeated { max, window }) = self.repeated {
+            let since = ts - micros(window);
+            if self.by_author.len() > PRUNE_ABOVE {
+                self.by_author
+                    .retain(|_, times| times.back().is_some_and(|t| *t > since));
+            }
+            let times = self.by_author.entry(author.clone()).or_default();
+            times.push_back(ts);
+            while times.front().is_some_and(|t| *t <= since) {
+                times.pop_front();
+            }
+            if times.len() > max {
+                triggered.push((author.clone(), Trigger::Repeated(times.len())));
+            }
+        }
+
+        if let Some(Burst { max, window }) = self.burst.filter(|_| new_account) {
+            let since = ts - micros(window);
+            self.new_accounts.push_back((ts, author.clone()));
+            while self.new_accounts.front().is_some_and(|(t, _)| *t <= since) {
+                self.new_accounts.pop_front();
+            }
+            let authors: HashSet<&Did> = self.new_accounts.iter().map(|(_, did)| did).collect();
+            if authors.len() > max {
+                let n = authors.len();
+                triggered.extend(
+                    authors
+                        .into_iter()
+                        .map(|did| (did.clone(), Trigger::Burst(n))),
+                );
+            }
+        }
+
+        triggered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use atrium_api::types::string::Did;
+
+    use super::{Burst, Rates, Repeated, Trigger};
+
+    #[test]
+    fn test_record() {
+        let did = |c: char| -> Did {
+            format!("did:plc:{}", c.to_string().repeat(24))
+                .parse()
+                .unwrap()
+        };
+        let (a, b, c) = (did('a'), did('b'), did('c'));
+        let minute = 60_000_000;
+
+        let mut rates = Rates::new(
+            Some(Repeated {
+                max: 2,
+                window: Duration::from_secs(3600),
+            }),
+            Some(Burst {
+                max: 2,
+                window: Duration::from_secs(600),
+            }),
+        );
+        assert!(rates.record(&a, 0, false).is_empty());
+        assert!(rates.record(&a, 10 * minute, false).is_empty());
+        assert_eq!(
+            rates.record(&a, 20 * minute, false),
+            vec![(a.clone(), Trigger::Repeated(3))]
+        );
+        // earlier replies are out of the window
+        assert!(rates.record(&a, 81 * minute, false).is_empty());
+
+        assert!(rates.record(&b, 100 * minute, true).is_empty());
+        assert!(rates.record(&c, 101 * minute, true).is_empty());
+        assert!(rates.record(&c, 102 * minute, true).is_empty());
+        let mut burst = rates.record(&a, 103 * minute, true);
+        burst.sort_by(|x, y| x.0.as_str().cmp(y.0.as_str()));
+        assert_eq!(
+            burst,
+            vec![
+                (a, Trigger::Burst(3)),
+                (b.clone(), Trigger::Burst(3)),
+                (c, Trigger::Burst(3))
+            ]
+        );
+        // the rest of the burst is out of the window
+        assert!(rates.record(&b, 113 * minute, true).is_empty());
+    }
+}
@@ -0,0 +1,386 @@
+//! Profile based rules
+//!
+//! Sources only yield dids: [`ProfileFilter::filter`] hydrates them by batches
+//! of [`MAX_PROFILES`] with `getProfiles`, then keeps those matching every
+//! `--only-if` rule and none of the `--skip-if` ones. Profiles are cached for
+//! a while, so that a did coming from several sources is only fetched once.
+//!
+//! Rules are written `<field><op><value>`:
+//! - `description~<regex>`, `display_name~<regex>`
+//! - `created_at<2024-01-01`, `indexed_at>2024-06-01T00:00:00Z`
+//! - `age>2y`, `age<30d`: since creation (or indexing), in days, weeks or years
+//! - `followers<10`, `follows>5000`, `posts<1`
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_stream::stream;
+use atrium_api::{
+    agent::store::SessionStore,
+    app::bsky::actor::{defs::ProfileViewDetailedData, get_profiles},
+    types::string::{AtIdentifier, Did},
+    xrpc::XrpcClient,
+};
+use bsky_sdk::BskyAgent;
+use chrono::{DateTime, FixedOffset, NaiveDate, TimeDelta, Utc};
+use clap::Args;
+use futures_core::Stream;
+use futures_util::{pin_mut, StreamExt};
+use ipld_core::ipld::Ipld;
+use regex::Regex;
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::followers::PageError;
+
+/// Max number of actors accepted by a single `getProfiles` call.
+pub const MAX_PROFILES: usize = 25;
+
+/// Number of cached profiles above which expired ones are dropped.
+const CACHE_SIZE: usize = 100_000;
+
+/// Time a cached profile is used for.
+const CACHE_TTL: Duration = Duration::from_secs(3600);
+
+/// Attempts at hydrating a batch before giving up on the stream.
+const RETRIES: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cmp {
+    Lt,
+    Gt,
+}
+
+impl Cmp {
+    fn test<T: PartialOrd>(self, value: T, bound: T) -> bool {
+        match self {
+            Cmp::Lt => value < bound,
+            Cmp::Gt => value > bound,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Predicate {
+    Description(Regex),
+    DisplayName(Regex),
+    CreatedAt(Cmp, DateTime<FixedOffset>),
+    IndexedAt(Cmp, DateTime<FixedOffset>),
+    Age(Cmp, TimeDelta),
+    Followers(Cmp, i64),
+    Follows(Cmp, i64),
+    Posts(Cmp, i64),
+}
+
+fn parse_date(s: &str) -> Result<DateTime<FixedOffset>, String> {
+    DateTime::parse_from_rfc3339(s)
+        .or_else(|_| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .map(|d| d.and_hms_opt(0, 0, 0).unwrap().and_utc().fixed_offset())
+        })
+        .map_err(|_| format!("invalid date: {s}, expected 2024-01-01 or RFC 3339"))
+}
+
+fn parse_age(s: &str) -> Result<TimeDelta, String> {
+    let err =
+        || format!("invalid age: {s}, expected a number of days, weeks or years (30d, 2w, 1y)");
+    let (n, unit) = s.split_at(s.len().saturating_sub(1));
+    let n: i64 = n.parse().map_err(|_| err())?;
+    match unit {
+        "d" => Ok(TimeDelta::days(n)),
+        "w" => Ok(TimeDelta::weeks(n)),
+        "y" => Ok(TimeDelta::days(365 * n)),
+        _ => Err(err()),
+    }
+}
+
+impl FromStr for Predicate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let at = s
+            .find(['~', '<', '>'])
+            .ok_or_else(|| format!("invalid rule: {s}, expected <field><~|<|>><value>"))?;
+        let (field, op, value) = (&s[..at], &s[at..at + 1], &s[at + 1..]);
+        let cmp = match op {
+            "<" => Some(Cmp::Lt),
+            ">" => Some(Cmp::Gt),
+            _ => None,
+        };
+        let regex = || Regex::new(value).map_err(|e| e.to_string());
+        let count = || {
+            value
+                .parse::<i64>()
+                .map_err(|_| format!("invalid count: {value}"))
+        };
+        match (field, cmp) {
+            ("description", None) => Ok(Predicate::Description(regex()?)),
+            ("display_name", None) => Ok(Predicate::DisplayName(regex()?)),
+            ("created_at", Some(cmp)) => Ok(Predicate::CreatedAt(cmp, parse_date(value)?)),
+            ("indexed_at", Some(cmp)) => Ok(Predicate::IndexedAt(cmp, parse_date(value)?)),
+            ("age", Some(cmp)) => Ok(Predicate::Age(cmp, parse_age(value)?)),
+            ("followers", Some(cmp)) => Ok(Predicate::Followers(cmp, count()?)),
+            ("follows", Some(cmp)) => Ok(Predicate::Follows(cmp, count()?)),
+            ("posts", Some(cmp)) => Ok(Predicate::Posts(cmp, count()?)),
+            _ => Err(format!("unsupported rule: {s}")),
+        }
+    }
+}
+
+impl Predicate {
+    /// Missing text or dates never match, missing counts are 0.
+    pub fn matches(&self, profile: &ProfileViewDetailedData, now: DateTime<Utc>) -> bool {
+        let created_at = profile.created_at.as_ref().map(|d| *d.as_ref());
+        let indexed_at = profile.indexed_at.as_ref().map(|d| *d.as_ref());
+        match self {
+            Predicate::Description(re) => profile
+                .description
+                .as_deref()
+                .is_some_and(|d| re.is_match(d)),
+            Predicate::DisplayName(re) => profile
+                .display_name
+                .as_deref()
+                .is_some_and(|d| re.is_match(d)),
+            Predicate::CreatedAt(cmp, date) => created_at.is_some_and(|d| cmp.test(d, *date)),
+            Predicate::IndexedAt(cmp, date) => indexed_at.is_some_and(|d| cmp.test(d, *date)),
+            Predicate::Age(cmp, age) => created_at
+                .or(indexed_at)
+                .is_some_and(|d| cmp.test(now.signed_duration_since(d), *age)),
+            Predicate::Followers(cmp, n) => cmp.test(profile.followers_count.unwrap_or(0), *n),
+            Predicate::Follows(cmp, n) => cmp.test(profile.follows_count.unwrap_or(0), *n),
+            Predicate::Posts(cmp, n) => cmp.test(profile.posts_count.unwrap_or(0), *n),
+        }
+    }
+}
+
+/// Command line flags of the profile rules.
+#[derive(Args, Debug, Clone)]
+pub struct ProfileFilterArgs {
+    /// only add accounts whose profile matches this rule, e.g. description~(?i)crypto
+    #[arg(long = "only-if")]
+    only_if: Vec<Predicate>,
+
+    /// never add accounts whose profile matches this rule, e.g. age>2y
+    #[arg(long = "skip-if")]
+    skip_if: Vec<Predicate>,
+}
+
+impl From<ProfileFilterArgs> for ProfileFilter {
+    fn from(args: ProfileFilterArgs) -> Self {
+        ProfileFilter::new(args.only_if, args.skip_if)
+    }
+}
+
+type Cached = (Instant, Option<Arc<ProfileViewDetailedData>>);
+
+#[derive(Debug, Default)]
+pub struct ProfileFilter {
+    only_if: Vec<Predicate>,
+    skip_if: Vec<Predicate>,
+    /// None for accounts without a profile (deleted, deactivated...)
+    cache: Mutex<HashMap<Did, Cached>>,
+}
+
+impl ProfileFilter {
+    pub fn new(only_if: Vec<Predicate>, skip_if: Vec<Predicate>) -> Self {
+        Self {
+            only_if,
+            skip_if,
+            cache: Mutex::default(),
+        }
+    }
+
+    /// Whether there are no rules: nothing gets hydrated then.
+    pub fn is_empty(&self) -> bool {
+        self.only_if.is_empty() && self.skip_if.is_empty()
+    }
+
+    pub fn matches(&self, profile: &ProfileViewDetailedData) -> bool {
+        let now = Utc::now();
+        self.only_if.iter().all(|p| p.matches(profile, now))
+            && !self.skip_if.iter().any(|p| p.matches(profile, now))
+    }
+
+    fn cached(&self, did: &Did) -> Option<Option<Arc<ProfileViewDetailedData>>> {
+        self.cache
+            .lock()
+            .unwrap()
+            .get(did)
+            .filter(|(at, _)| at.elapsed() < CACHE_TTL)
+            .map(|(_, profile)| profile.clone())
+    }
+
+    fn store(&self, did: Did, profile: Option<Arc<ProfileViewDetailedData>>) {
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= CACHE_SIZE {
+            cache.retain(|_, (at, _)| at.elapsed() < CACHE_TTL);
+            if cache.len() >= CACHE_SIZE {
+                cache.clear();
+            }
+        }
+        cache.insert(did, (Instant::now(), profile));
+    }
+
+    /// Gets profiles, from the cache or in batches of [`MAX_PROFILES`].
+    /// None for accounts without a profile.
+    pub async fn hydrate<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &self,
+        agent: &BskyAgent<T, S>,
+        dids: &[Did],
+    ) -> Result<Vec<Option<Arc<ProfileViewDetailedData>>>, Box<dyn Error>> {
+        let missing: Vec<Did> = dids
+            .iter()
+            .filter(|did| self.cached(did).is_none())
+            .cloned()
+            .collect();
+        for chunk in missing.chunks(MAX_PROFILES) {
+            let profiles = agent
+                .api
+                .app
+                .bsky
+                .actor
+                .get_profiles(get_profiles::Parameters {
+                    data: get_profiles::ParametersData {
+                        actors: chunk.iter().cloned().map(AtIdentifier::Did).collect(),
+                    },
+                    extra_data: Ipld::Null,
+                })
+                .await?
+                .data
+                .profiles;
+            let mut profiles: HashMap<Did, ProfileViewDetailedData> = profiles
+                .into_iter()
+                .map(|p| (p.data.did.clone(), p.data))
+                .collect();
+            for did in chunk {
+                self.store(did.clone(), profiles.remove(did).map(Arc::new));
+            }
+        }
+        Ok(dids.iter().map(|did| self.cached(did).flatten()).collect())
+    }
+
+    /// Keeps the dids whose profile matches the rules, from a stream of (did, cursor).
+    ///
+    /// Dids are hydrated by batches of those ready, so that a live source isn't delayed.
+    /// Accounts without a profile are dropped. A batch that still can't be hydrated after
+    /// [`RETRIES`] attempts ends the stream with an error, for callers to resume from
+    /// their last written cursor.
+    pub fn filter<'a, C: 'a, T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &'a self,
+        agent: &'a BskyAgent<T, S>,
+        dids: impl Stream<Item = (Did, C)> + 'a,
+    ) -> impl Stream<Item = Result<(Did, C), PageError>> + 'a {
+        stream! {
+            let chunks = dids.ready_chunks(MAX_PROFILES);
+            pin_mut!(chunks);
+            while let Some(chunk) = chunks.next().await {
+                if self.is_empty() {
+                    for item in chunk {
+                        yield Ok(item);
+                    }
+                    continue;
+                }
+
+                let batch: Vec<Did> = chunk.iter().map(|(did, _)| did.clone()).collect();
+                let mut profiles = Err(PageError(String::new()));
+                for attempt in 1..=RETRIES {
+                    // errors aren't Send, only keep their message
+                    profiles = self
+                        .hydrate(agent, &batch)
+                        .await
+                        .map_err(|e| PageError(e.to_string()));
+                    match &profiles {
+                        Ok(_) => break,
+                        Err(e) => {
+                            warn!(msg = "could not get profiles", attempt = attempt, err = %e);
+                            if attempt < RETRIES {
+                                time::sleep(Duration::from_secs(5 * attempt as u64)).await;
+                            }
+                        }
+                    }
+                }
+                let profiles = match profiles {
+                    Ok(p) => p,
+                    Err(e) => {
+                        yield Err(e);
+                        break;
+                    }
+                };
+
+                for (item, profile) in chunk.into_iter().zip(profiles) {
+                    match profile {
+                        Some(p) if self.matches(&p) => yield Ok(item),
+                        Some(_) => info!(msg = "skipped by profile rules", did = ?item.0),
+                        None => info!(msg = "no profile, skipping", did = ?item.0),
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use atrium_api::app::bsky::actor::defs::ProfileViewDetailedData;
+    use chrono::{TimeDelta, Utc};
+
+    use super::{Cmp, Predicate, ProfileFilter};
+
+    fn profile(description: &str, created_at: &str, followers: i64) -> ProfileViewDetailedData {
+        ProfileViewDetailedData {
+            associated: None,
+            avatar: None,
+            banner: None,
+            created_at: Some(created_at.parse().unwrap()),
+            description: Some(description.to_string()),
+            did: "did:plc:aaaaaaaaaaaaaaaaaaaaaaaa".parse().unwrap(),
+            display_name: None,
+            followers_count: Some(followers),
+            follows_count: None,
+            handle: "foo.bsky.social".parse().unwrap(),
+            indexed_at: None,
+            joined_via_starter_pack: None,
+            labels: None,
+            pinned_post: None,
+            posts_count: None,
+            viewer: None,
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(matches!(
+            "age>2y".parse(),
+            Ok(Predicate::Age(Cmp::Gt, d)) if d == TimeDelta::days(730)
+        ));
+        assert!(matches!(
+            "followers<10".parse(),
+            Ok(Predicate::Followers(Cmp::Lt, 10))
+        ));
+        assert!("description~(?i)nft|crypto".parse::<Predicate>().is_ok());
+        assert!("created_at<2024-01-01".parse::<Predicate>().is_ok());
+        assert!("age~2y".parse::<Predicate>().is_err());
+        assert!("karma>3".parse::<Predicate>().is_err());
+        assert!("followers".parse::<Predicate>().is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        let filter = ProfileFilter::new(
+            vec!["description~(?i)crypto".parse().unwrap()],
+            vec!["age>2y".parse().unwrap()],
+        );
+        let recent = (Utc::now() - TimeDelta::days(30)).to_rfc3339();
+        assert!(filter.matches(&profile("Crypto enjoyer", &recent, 3)));
+        assert!(!filter.matches(&profile("gardening", &recent, 3)));
+        assert!(!filter.matches(&profile("crypto", "2020-01-01T00:00:00Z", 3)));
+
+        let p: Predicate = "followers>2".parse().unwrap();
+        assert!(p.matches(&profile("", &recent, 3), Utc::now()));
+    }
+}
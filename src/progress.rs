@@ -0,0 +1,154 @@
+//! Backfill progress reporting
+//!
+//! Totals come from the watched profile's followers count and the list's
+//! members count. Counts start at zero when resuming from a cursor, so the
+//! ETA is an upper bound in that case.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use indicatif::{ProgressBar, ProgressStyle};
+use tracing::info;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Report {
+    pub processed: u64,
+    pub added: u64,
+    pub skipped: u64,
+    /// processed per second
+    pub rate: f64,
+    pub eta: Option<Duration>,
+}
+
+impl Report {
+    fn new(processed: u64, added: u64, total: Option<u64>, elapsed: Duration) -> Self {
+        let secs = elapsed.as_secs_f64();
+        let rate = match secs > 0.0 {
+            true => processed as f64 / secs,
+            false => 0.0,
+        };
+        let eta = match (total, rate > 0.0) {
+            (Some(total), true) => Some(Duration::from_secs_f64(
+                total.saturating_sub(processed) as f64 / rate,
+            )),
+            _ => None,
+        };
+        Self {
+            processed,
+            added,
+            skipped: processed.saturating_sub(added),
+            rate,
+            eta,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Progress {
+    /// number of followers to go through
+    total: Option<u64>,
+    /// number of members of the list when starting
+    members: Option<u64>,
+    start: Instant,
+    processed: AtomicU64,
+    added: AtomicU64,
+    bar: Option<ProgressBar>,
+}
+
+impl Progress {
+    pub fn new(total: Option<u64>, members: Option<u64>) -> Self {
+        Self {
+            total,
+            members,
+            start: Instant::now(),
+            processed: AtomicU64::new(0),
+            added: AtomicU64::new(0),
+            bar: None,
+        }
+    }
+
+    /// Also draws a progress bar on the terminal.
+    pub fn with_bar(mut self) -> Self {
+        let bar = match self.total {
+            Some(total) => ProgressBar::new(total),
+            None => ProgressBar::no_length(),
+        };
+        bar.set_style(
+            ProgressStyle::with_template(
+                "{bar:40} {pos}/{len} followers ({per_sec}, eta {eta}) {msg}",
+            )
+            .unwrap(),
+        );
+        self.bar = Some(bar);
+        self
+    }
+
+    /// A follower was read.
+    pub fn inc_processed(&self) {
+        self.processed.fetch_add(1, Ordering::Relaxed);
+        if let Some(bar) = &self.bar {
+            bar.inc(1);
+        }
+    }
+
+    /// A follower was queued for writing to the list.
+    pub fn inc_added(&self) {
+        let added = self.added.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Some(bar) = &self.bar {
+            bar.set_message(format!("{added} added"));
+        }
+    }
+
+    pub fn report(&self) -> Report {
+        Report::new(
+            self.processed.load(Ordering::Relaxed),
+            self.added.load(Ordering::Relaxed),
+            self.total,
+            self.start.elapsed(),
+        )
+    }
+
+    pub fn log(&self) {
+        let r = self.report();
+        info!(
+            msg = "backfill progress",
+            processed = r.processed,
+            total = ?self.total,
+            added = r.added,
+            skipped = r.skipped,
+            members_at_start = ?self.members,
+            per_sec = format!("{:.1}", r.rate),
+            eta = ?r.eta.map(|d| Duration::from_secs(d.as_secs())),
+        );
+    }
+
+    pub fn finish(&self) {
+        if let Some(bar) = &self.bar {
+            bar.finish();
+        }
+        self.log();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Report;
+
+    #[test]
+    fn test_report() {
+        let r = Report::new(100, 40, Some(1000), Duration::from_secs(10));
+        assert_eq!(r.skipped, 60);
+        assert_eq!(r.rate, 10.0);
+        assert_eq!(r.eta, Some(Duration::from_secs(90)));
+
+        let r = Report::new(0, 0, Some(1000), Duration::ZERO);
+        assert_eq!(r.eta, None);
+
+        let r = Report::new(1200, 0, Some(1000), Duration::from_secs(10));
+        assert_eq!(r.eta, Some(Duration::ZERO));
+    }
+}
@@ -0,0 +1,225 @@
+//! Resolves user input to dids and AT-URIs
+//!
+//! Accounts, lists, feeds and starter packs can be given as handles
+//! (`@foo.bsky.social`), dids, `https://bsky.app/profile/<x>/lists/<rkey>` or
+//! `https://bsky.app/starter-pack/<x>/<rkey>` style links or AT-URIs.
+//! Handles are resolved to dids so that the output is stable.
+
+use std::error::Error;
+
+use atrium_api::{
+    agent::store::SessionStore,
+    app::bsky::{
+        feed::Generator,
+        graph::{List, Starterpack},
+    },
+    com::atproto::identity::resolve_handle,
+    types::{
+        string::{AtIdentifier, Did},
+        Collection,
+    },
+    xrpc::XrpcClient,
+};
+use bsky_sdk::BskyAgent;
+use ipld_core::ipld::Ipld;
+use url::Url;
+
+/// A parsed, not yet resolved, reference to an account or one of its records.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Reference {
+    pub authority: AtIdentifier,
+    pub collection: Option<String>,
+    pub rkey: Option<String>,
+}
+
+/// Hosts of the bsky app whose links are accepted.
+const APP_HOSTS: [&str; 3] = ["bsky.app", "www.bsky.app", "staging.bsky.app"];
+
+/// Maps bsky.app path segments to collections.
+fn collection_of(segment: &str) -> Option<&'static str> {
+    match segment {
+        "lists" => Some(List::NSID),
+        "feed" => Some(Generator::NSID),
+        "post" => Some("app.bsky.feed.post"),
+        _ => None,
+    }
+}
+
+fn parse_authority(authority: &str) -> Result<AtIdentifier, Box<dyn Error>> {
+    let authority = authority.trim_start_matches('@');
+    Ok(match authority.starts_with("did:") {
+        true => AtIdentifier::Did(authority.parse()?),
+        false => AtIdentifier::Handle(authority.parse()?),
+    })
+}
+
+/// Parses a handle, did, bsky.app link or AT-URI without any network call.
+pub fn parse(input: &str) -> Result<Reference, Box<dyn Error>> {
+    let input = input.trim();
+
+    if let Some(path) = input.strip_prefix("at://") {
+        let mut parts = path.trim_end_matches('/').splitn(3, '/');
+        let authority = parse_authority(parts.next().unwrap_or_default())?;
+        return Ok(Reference {
+            authority,
+            collection: parts.next().map(String::from),
+            rkey: parts.next().map(String::from),
+        });
+    }
+
+    if input.starts_with("https://") || input.starts_with("http://") {
+        let url: Url = input.parse()?;
+        if !url.host_str().is_some_and(|h| APP_HOSTS.contains(&h)) {
+            return Err(format!("not a bsky.app link: {input}").into());
+        }
+        let segments: Vec<_> = url
+            .path_segments()
+            .map(|s| s.filter(|s| !s.is_empty()).collect())
+            .unwrap_or_default();
+        return match segments.as_slice() {
+            ["profile", authority] => Ok(Reference {
+                authority: parse_authority(authority)?,
+                collection: None,
+                rkey: None,
+            }),
+            ["profile", authority, kind, rkey] => {
+                let collection = collection_of(kind).ok_or(format!("unsupported link: {input}"))?;
+                Ok(Reference {
+                    authority: parse_authority(authority)?,
+                    collection: Some(collection.to_string()),
+                    rkey: Some(rkey.to_string()),
+                })
+            }
+            ["starter-pack", authority, rkey] => Ok(Reference {
+                authority: parse_authority(authority)?,
+                collection: Some(Starterpack::NSID.to_string()),
+                rkey: Some(rkey.to_string()),
+            }),
+            _ => Err(format!("unsupported link: {input}").into()),
+        };
+    }
+
+    Ok(Reference {
+        authority: parse_authority(input)?,
+        collection: None,
+        rkey: None,
+    })
+}
+
+/// Resolves a handle to its did, dids are returned as is.
+pub async fn resolve_did<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+    agent: &BskyAgent<T, S>,
+    identifier: AtIdentifier,
+) -> Result<Did, Box<dyn Error>> {
+    match identifier {
+        AtIdentifier::Did(did) => Ok(did),
+        AtIdentifier::Handle(handle) => Ok(agent
+            .api
+            .com
+            .atproto
+            .identity
+            .resolve_handle(resolve_handle::Parameters {
+                data: resolve_handle::ParametersData { handle },
+                extra_data: Ipld::Null,
+            })
+            .await?
+            .data
+            .did),
+    }
+}
+
+/// Resolves an account given as handle, did or profile link to its did.
+pub async fn resolve_actor<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+    agent: &BskyAgent<T, S>,
+    input: &str,
+) -> Result<Did, Box<dyn Error>> {
+    let reference = parse(input)?;
+    if reference.collection.is_some() {
+        return Err(format!("expected an account, got a record: {input}").into());
+    }
+    resolve_did(agent, reference.authority).await
+}
+
+/// Resolves a record reference to an AT-URI with a did authority.
+async fn resolve_record<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+    agent: &BskyAgent<T, S>,
+    input: &str,
+    collection: &str,
+) -> Result<String, Box<dyn Error>> {
+    let reference = parse(input)?;
+    let rkey = match (reference.collection.as_deref(), reference.rkey) {
+        (Some(c), Some(rkey)) if c == collection => rkey,
+        _ => return Err(format!("expected a {collection} record: {input}").into()),
+    };
+    let did = resolve_did(agent, reference.authority).await?;
+    Ok(format!("at://{}/{collection}/{rkey}", did.as_str()))
+}
+
+/// Resolves a list link or AT-URI to an AT-URI.
+pub async fn resolve_list<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+    agent: &BskyAgent<T, S>,
+    input: &str,
+) -> Result<String, Box<dyn Error>> {
+    resolve_record(agent, input, List::NSID).await
+}
+
+/// Resolves a feed generator link or AT-URI to an AT-URI.
+pub async fn resolve_feed<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+    agent: &BskyAgent<T, S>,
+    input: &str,
+) -> Result<String, Box<dyn Error>> {
+    resolve_record(agent, input, Generator::NSID).await
+}
+
+/// Resolves a starter pack link or AT-URI to an AT-URI.
+pub async fn resolve_starter_pack<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+    agent: &BskyAgent<T, S>,
+    input: &str,
+) -> Result<String, Box<dyn Error>> {
+    resolve_record(agent, input, Starterpack::NSID).await
+}
+
+#[cfg(test)]
+mod tests {
+    use atrium_api::types::string::AtIdentifier;
+
+    use super::parse;
+
+    #[test]
+    fn test_parse() {
+        let handle = AtIdentifier::Handle("foo.bsky.social".parse().unwrap());
+        let did = AtIdentifier::Did("did:plc:hhj2b7rqtaffsbd7a52dhf4j".parse().unwrap());
+
+        let r = parse("@foo.bsky.social").unwrap();
+        assert_eq!(r.authority, handle);
+        assert_eq!(r.collection, None);
+
+        let r = parse("did:plc:hhj2b7rqtaffsbd7a52dhf4j").unwrap();
+        assert_eq!(r.authority, did);
+
+        let r = parse("https://bsky.app/profile/foo.bsky.social/lists/3lbd7snb23r2y").unwrap();
+        assert_eq!(r.authority, handle);
+        assert_eq!(r.collection.as_deref(), Some("app.bsky.graph.list"));
+        assert_eq!(r.rkey.as_deref(), Some("3lbd7snb23r2y"));
+
+        let r =
+            parse("https://bsky.app/profile/did:plc:hhj2b7rqtaffsbd7a52dhf4j/feed/aaa").unwrap();
+        assert_eq!(r.authority, did);
+        assert_eq!(r.collection.as_deref(), Some("app.bsky.feed.generator"));
+
+        let r = parse("at://did:plc:hhj2b7rqtaffsbd7a52dhf4j/app.bsky.graph.list/3lbd7snb23r2y")
+            .unwrap();
+        assert_eq!(r.authority, did);
+        assert_eq!(r.collection.as_deref(), Some("app.bsky.graph.list"));
+        assert_eq!(r.rkey.as_deref(), Some("3lbd7snb23r2y"));
+
+        let r = parse("https://bsky.app/starter-pack/foo.bsky.social/3l5f3lbiu5p2e").unwrap();
+        assert_eq!(r.authority, handle);
+        assert_eq!(r.collection.as_deref(), Some("app.bsky.graph.starterpack"));
+        assert_eq!(r.rkey.as_deref(), Some("3l5f3lbiu5p2e"));
+
+        assert!(parse("https://bsky.app/search?q=foo").is_err());
+        assert!(parse("https://evil.example/profile/foo.bsky.social").is_err());
+        assert!(parse("not a handle").is_err());
+    }
+}
@@ -0,0 +1,93 @@
+//! from post search
+//!
+//! Backfills a keyword rule: live post watching only sees posts made from now on.
+
+use async_stream::stream;
+use atrium_api::{
+    agent::store::SessionStore,
+    app::bsky::feed::{defs::PostViewData, search_posts},
+    types::{string::Language, LimitedNonZeroU8, Object},
+    xrpc::XrpcClient,
+};
+use bsky_sdk::BskyAgent;
+use futures_core::Stream;
+use ipld_core::ipld::Ipld;
+use tracing::info;
+
+use crate::followers::PageError;
+
+/// A `searchPosts` query.
+#[derive(Debug, Clone, Default)]
+pub struct Search {
+    /// lucene-like query
+    pub query: String,
+    /// posts from that date or datetime, inclusive
+    pub since: Option<String>,
+    /// posts before that date or datetime
+    pub until: Option<String>,
+    pub lang: Option<Language>,
+}
+
+/// Posts matching a search, newest first, along with the cursor of the next page.
+///
+/// The server may not allow paginating through every hit, the stream ends
+/// with the last page it returns, or with the error of the first page that can't be read.
+pub async fn from_search<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+    agent: &BskyAgent<T, S>,
+    search: Search,
+    cursor: Option<String>,
+) -> impl Stream<Item = Result<(Object<PostViewData>, Option<String>), PageError>> + '_ {
+    let get_batch = move |cursor: Option<String>| {
+        let search = search.clone();
+        async move {
+            agent
+                .api
+                .app
+                .bsky
+                .feed
+                .search_posts(search_posts::Parameters {
+                    data: search_posts::ParametersData {
+                        author: None,
+                        cursor,
+                        domain: None,
+                        lang: search.lang,
+                        limit: Some(LimitedNonZeroU8::MAX),
+                        mentions: None,
+                        q: search.query,
+                        since: search.since,
+                        sort: Some("latest".to_string()),
+                        tag: None,
+                        until: search.until,
+                        url: None,
+                    },
+                    extra_data: Ipld::Null,
+                })
+                .await
+        }
+    };
+
+    stream! {
+        let mut cursor = cursor;
+        for i in 0.. {
+            let batch = match get_batch(cursor).await {
+                Ok(batch) => batch,
+                Err(e) => {
+                    yield Err(PageError(e.to_string()));
+                    break;
+                }
+            };
+            info!(msg="getting batch", nb=i, cursor=?batch.cursor, hits=?batch.hits_total);
+            cursor = batch.cursor.clone();
+            info!(msg="got posts", nb=&batch.data.posts.len());
+            if batch.data.posts.is_empty() {
+                break;
+            }
+            for post in batch.data.posts {
+                yield Ok((post, cursor.clone()));
+            }
+            if cursor.is_none() {
+                break;
+            }
+        }
+    }
+}
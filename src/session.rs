@@ -0,0 +1,324 @@
+//! Session persistence and re-login
+//!
+//! The agent refreshes its access token on its own, but only in memory: a
+//! restarted daemon would load the stale refresh token from `config.json`.
+//! [`FileSessionStore`] writes every session update back to the config file,
+//! and [`Credentials`] allow logging in again once the refresh token is dead.
+
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use atrium_api::{
+    agent::{
+        store::{MemorySessionStore, SessionStore},
+        Session,
+    },
+    com::atproto::server::create_session,
+    xrpc::XrpcClient,
+};
+use bsky_sdk::{
+    agent::config::{Config, FileStore},
+    BskyAgent,
+};
+use clap::Args;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/// Error names returned by the PDS when the session can't be used anymore.
+const AUTH_ERRORS: [&str; 3] = ["ExpiredToken", "InvalidToken", "AuthenticationRequired"];
+
+/// Error name returned on login when the account has email 2FA enabled.
+const AUTH_FACTOR_ERROR: &str = "AuthFactorTokenRequired";
+
+/// Session store that saves the config file on each session update.
+#[derive(Clone)]
+pub struct FileSessionStore {
+    inner: MemorySessionStore,
+    path: PathBuf,
+    config: Arc<Mutex<Config>>,
+}
+
+impl FileSessionStore {
+    pub fn new(path: impl AsRef<Path>, config: Config) -> Self {
+        Self {
+            inner: MemorySessionStore::default(),
+            path: path.as_ref().to_path_buf(),
+            config: Arc::new(Mutex::new(config)),
+        }
+    }
+}
+
+impl SessionStore for FileSessionStore {
+    async fn get_session(&self) -> Option<Session> {
+        self.inner.get_session().await
+    }
+
+    async fn set_session(&self, session: Session) {
+        self.inner.set_session(session.clone()).await;
+        let mut config = self.config.lock().await;
+        config.session = Some(session);
+        match config.save(&FileStore::new(&self.path)).await {
+            Ok(()) => info!(msg = "saved session", location = ?self.path),
+            Err(e) => warn!(msg = "could not save session", location = ?self.path, err = %e),
+        }
+    }
+
+    /// Only clears the in-memory session: the file is overwritten on next login.
+    async fn clear_session(&self) {
+        self.inner.clear_session().await
+    }
+}
+
+/// Login credentials, used when the stored session can't be refreshed.
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    identifier: String,
+    app_password: String,
+    auth_factor_token: Option<String>,
+}
+
+impl Credentials {
+    pub fn new(identifier: String, app_password: String) -> Self {
+        Self {
+            identifier,
+            app_password,
+            auth_factor_token: None,
+        }
+    }
+
+    /// Sets the 2FA token received by email.
+    pub fn with_auth_factor_token(mut self, token: String) -> Self {
+        self.auth_factor_token = Some(token);
+        self
+    }
+
+    /// Reads the app password from the first line of a secrets file.
+    pub fn from_file(identifier: String, path: &Path) -> Result<Self, Box<dyn Error>> {
+        let app_password = std::fs::read_to_string(path)?
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_string();
+        if app_password.is_empty() {
+            return Err(format!("empty app password file: {}", path.display()).into());
+        }
+        Ok(Self::new(identifier, app_password))
+    }
+
+    pub fn identifier(&self) -> &str {
+        &self.identifier
+    }
+
+    pub async fn login<T, S>(&self, agent: &BskyAgent<T, S>) -> Result<(), Box<dyn Error>>
+    where
+        T: XrpcClient + Send + Sync,
+        S: SessionStore + Send + Sync,
+    {
+        info!(msg = "logging in", identifier = self.identifier);
+        match &self.auth_factor_token {
+            None => {
+                agent.login(&self.identifier, &self.app_password).await?;
+            }
+            Some(token) => {
+                let session = agent
+                    .api
+                    .com
+                    .atproto
+                    .server
+                    .create_session(
+                        create_session::InputData {
+                            auth_factor_token: Some(token.clone()),
+                            identifier: self.identifier.clone(),
+                            password: self.app_password.clone(),
+                        }
+                        .into(),
+                    )
+                    .await?;
+                agent.resume_session(session).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Command line flags of long running commands, to log in again once the session is dead.
+#[derive(Args, Debug, Clone)]
+pub struct ReloginArgs {
+    /// handle or did used to log in again if the session expires (defaults to the config's)
+    #[arg(long, env)]
+    identifier: Option<String>,
+
+    /// app password used to log in again if the session expires
+    #[arg(long, env, hide_env_values = true)]
+    app_password: Option<String>,
+
+    /// file containing the app password, read if --app-password is not set
+    #[arg(long, env)]
+    app_password_file: Option<PathBuf>,
+}
+
+impl ReloginArgs {
+    /// Gets re-login credentials, falling back on the config's session for the identifier.
+    pub async fn credentials(self, config: &Path) -> Result<Option<Credentials>, Box<dyn Error>> {
+        let identifier = match self.identifier {
+            Some(i) => Some(i),
+            None => Config::load(&FileStore::new(config))
+                .await
+                .ok()
+                .and_then(|c| c.session)
+                .map(|s| s.data.did.to_string()),
+        };
+        let credentials = match (identifier, self.app_password, self.app_password_file) {
+            (Some(id), Some(password), _) => Some(Credentials::new(id, password)),
+            (Some(id), None, Some(path)) => Some(Credentials::from_file(id, &path)?),
+            (None, Some(_), _) | (None, None, Some(_)) => {
+                warn!(msg = "app password provided without identifier, ignoring");
+                None
+            }
+            _ => None,
+        };
+        Ok(credentials)
+    }
+}
+
+/// Logs in again if possible, returning whether the failed operation should be retried.
+pub async fn relogin<T, S>(agent: &BskyAgent<T, S>, credentials: Option<&Credentials>) -> bool
+where
+    T: XrpcClient + Send + Sync,
+    S: SessionStore + Send + Sync,
+{
+    let Some(credentials) = credentials else {
+        warn!(msg = "session expired and no app password to log in again");
+        return false;
+    };
+    match credentials.login(agent).await {
+        Ok(()) => true,
+        Err(e) => {
+            warn!(msg = "could not log in again", err = %e);
+            false
+        }
+    }
+}
+
+/// Forces a session refresh, storing the new tokens.
+pub async fn refresh<T, S>(agent: &BskyAgent<T, S>) -> Result<(), Box<dyn Error>>
+where
+    T: XrpcClient + Send + Sync,
+    S: SessionStore + Send + Sync,
+{
+    let mut session = agent.get_session().await.ok_or("not logged in")?;
+    let refreshed = agent.api.com.atproto.server.refresh_session().await?;
+    session.access_jwt = refreshed.data.access_jwt;
+    session.refresh_jwt = refreshed.data.refresh_jwt;
+    session.handle = refreshed.data.handle;
+    session.did_doc = refreshed.data.did_doc;
+    agent.resume_session(session).await?;
+    Ok(())
+}
+
+/// Whether the error comes from an expired or revoked session.
+pub fn is_auth_error(err: &dyn Error) -> bool {
+    let err = err.to_string();
+    AUTH_ERRORS.iter().any(|e| err.contains(e))
+}
+
+/// Whether the error may go away by retrying later: rate limits, server or network errors.
+pub fn is_transient(err: &dyn Error) -> bool {
+    let err = err.to_string();
+    match err.strip_prefix("xrpc response error: ") {
+        // bsky-sdk only keeps the status of responses, and the debug output of other errors
+        Some(status) => {
+            status.starts_with("429")
+                || status.starts_with('5')
+                || status.starts_with("HttpClient(")
+        }
+        None => err.starts_with("http client error"),
+    }
+}
+
+/// Whether the login failed because the account requires an email 2FA token.
+pub fn is_auth_factor_required(err: &dyn Error) -> bool {
+    err.to_string().contains(AUTH_FACTOR_ERROR)
+}
+
+/// Builds an agent whose session is persisted to the config at `path`.
+///
+/// If the stored session can't be resumed, logs in again with `credentials` when provided.
+pub async fn load_agent<T: XrpcClient + Send + Sync>(
+    client: T,
+    path: impl AsRef<Path>,
+    credentials: Option<&Credentials>,
+) -> Result<BskyAgent<T, FileSessionStore>, Box<dyn Error>> {
+    let path = path.as_ref();
+    let mut config = Config::load(&FileStore::new(path)).await?;
+    let session = config.session.take();
+    let store = FileSessionStore::new(path, config.clone());
+    let agent = BskyAgent::builder()
+        .config(config)
+        .client(client)
+        .store(store)
+        .build()
+        .await?;
+
+    let resumed = match session {
+        Some(session) => match agent.resume_session(session).await {
+            Ok(()) => true,
+            Err(e) => {
+                warn!(msg = "could not resume session", err = %e);
+                false
+            }
+        },
+        None => false,
+    };
+
+    if !resumed {
+        match credentials {
+            Some(credentials) => credentials.login(&agent).await?,
+            None => return Err("no valid session and no app password to log in with".into()),
+        }
+    }
+    Ok(agent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_auth_error, is_transient};
+
+    #[test]
+    fn test_is_auth_error() {
+        let err: Box<dyn std::error::Error> =
+            "xrpc response error: 400 ExpiredToken: Token has expired".into();
+        assert!(is_auth_error(err.as_ref()));
+
+        let err: Box<dyn std::error::Error> = "xrpc response error: 429 RateLimitExceeded".into();
+        assert!(!is_auth_error(err.as_ref()));
+    }
+
+    #[test]
+    fn test_is_transient() {
+        let err: Box<dyn std::error::Error> = "xrpc response error: 429 RateLimitExceeded".into();
+        assert!(is_transient(err.as_ref()));
+
+        let err: Box<dyn std::error::Error> = "xrpc response error: 502".into();
+        assert!(is_transient(err.as_ref()));
+
+        let err: Box<dyn std::error::Error> =
+            "xrpc response error: 400 InvalidRequest: Invalid did".into();
+        assert!(!is_transient(err.as_ref()));
+
+        // network failure of a record write
+        let err: Box<dyn std::error::Error> = bsky_sdk::Error::from(
+            atrium_api::xrpc::Error::<()>::HttpClient("error sending request".into()),
+        )
+        .into();
+        assert_eq!(
+            err.to_string(),
+            "xrpc response error: HttpClient(\"error sending request\")"
+        );
+        assert!(is_transient(err.as_ref()));
+    }
+}
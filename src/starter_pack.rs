@@ -0,0 +1,138 @@
+//! from starter pack
+//!
+//! A starter pack is a list plus a few feeds: its members are the list's.
+//! Joining through a pack follows all of its members, and profiles tell which
+//! pack an account joined with, so joiners are looked for among the followers
+//! of the pack's creator.
+
+use std::error::Error;
+
+use async_stream::stream;
+use atrium_api::{
+    agent::store::SessionStore,
+    app::bsky::{
+        actor::get_profiles,
+        graph::{defs::StarterPackViewData, get_starter_pack},
+    },
+    types::string::{AtIdentifier, Did},
+    xrpc::XrpcClient,
+};
+use bsky_sdk::BskyAgent;
+use futures_core::Stream;
+use futures_util::{pin_mut, StreamExt, TryStreamExt};
+use ipld_core::ipld::Ipld;
+use tracing::info;
+
+use crate::{
+    followers::{from_followers, PageError},
+    list::List,
+    profile_filter::MAX_PROFILES,
+};
+
+/// gets creator, list and join counts of provided starter pack.
+pub async fn get_view<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+    agent: &BskyAgent<T, S>,
+    starter_pack: String,
+) -> Result<StarterPackViewData, Box<dyn Error>> {
+    Ok(agent
+        .api
+        .app
+        .bsky
+        .graph
+        .get_starter_pack(get_starter_pack::Parameters {
+            data: get_starter_pack::ParametersData { starter_pack },
+            extra_data: Ipld::Null,
+        })
+        .await?
+        .data
+        .starter_pack
+        .data)
+}
+
+/// Members of the starter pack's list, until the first page that can't be read.
+///
+/// Lists are read in one go, items carry no cursor.
+pub async fn from_members<'a, T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+    agent: &'a BskyAgent<T, S>,
+    view: &StarterPackViewData,
+) -> Result<impl Stream<Item = Result<(Did, Option<String>), PageError>> + 'a, Box<dyn Error>> {
+    let list = view
+        .list
+        .as_ref()
+        .ok_or(format!("starter pack without a list: {}", view.uri))?;
+    Ok(List::get_members(list.uri.clone(), agent, None)
+        .await
+        .map_ok(|m| (m.did, None)))
+}
+
+/// Followers of the pack's creator who joined through it, with the cursor
+/// of the next page of followers.
+///
+/// Stops once as many joiners as the pack counts have been found, joiners
+/// who since unfollowed the creator are missed. Ends with the error of the
+/// first followers or profiles that can't be read.
+pub async fn from_joined<'a, T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+    agent: &'a BskyAgent<T, S>,
+    view: &StarterPackViewData,
+    cursor: Option<String>,
+) -> impl Stream<Item = Result<(Did, Option<String>), PageError>> + 'a {
+    let pack = view.uri.clone();
+    let creator = view.creator.did.clone();
+    let nb_joined = view.joined_all_time_count;
+
+    let get_profiles = move |actors: Vec<AtIdentifier>| async move {
+        agent
+            .api
+            .app
+            .bsky
+            .actor
+            .get_profiles(get_profiles::Parameters {
+                data: get_profiles::ParametersData { actors },
+                extra_data: Ipld::Null,
+            })
+            .await
+    };
+
+    stream! {
+        let followers = from_followers(agent, AtIdentifier::Did(creator), cursor)
+            .await
+            .chunks(MAX_PROFILES);
+        pin_mut!(followers);
+        let mut found = 0;
+        while let Some(chunk) = followers.next().await {
+            let chunk = match chunk.into_iter().collect::<Result<Vec<_>, _>>() {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    yield Err(e);
+                    break;
+                }
+            };
+            let actors = chunk
+                .iter()
+                .map(|(f, _)| AtIdentifier::Did(f.did.clone()))
+                .collect();
+            let profiles = match get_profiles(actors).await {
+                Ok(output) => output.data.profiles,
+                Err(e) => {
+                    yield Err(PageError(e.to_string()));
+                    break;
+                }
+            };
+            // profiles come back in any order, missing for deleted accounts
+            for (follower, cursor) in chunk {
+                let joined = profiles.iter().any(|p| {
+                    p.did == follower.did
+                        && p.joined_via_starter_pack.as_ref().is_some_and(|s| s.uri == pack)
+                });
+                if joined {
+                    found += 1;
+                    yield Ok((follower.data.did, cursor));
+                }
+            }
+            if nb_joined.is_some_and(|nb| found >= nb) {
+                info!(msg = "found every joiner", nb = found);
+                break;
+            }
+        }
+    }
+}
@@ -1,30 +1,53 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
 
-use atrium_api::types::string::{AtIdentifier, Cid, Did};
+use atrium_api::types::string::Did;
 use serde::{Deserialize, Serialize};
+use tracing::info;
 
-use crate::modlist::ModList;
+use crate::list::List;
 
-pub type States = HashMap<Did, State>;
+/// States by did, followed by the scope of the store if any.
+pub type States = HashMap<String, State>;
 
 /// Did->modlist state:
 /// last cursor returned when backfilling
 /// last timestamp delivered by the jetstream
+/// position in a follower graph walk, for dids that get expanded
 ///
 /// Those can be approximate since we'll likely won't be writing ts+cursor at each update.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct State {
-    pub modlist: ModList,
+    pub modlist: List,
     cursor: Option<String>,
     jetstream_ts: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    walk: Option<WalkNode>,
+}
+
+/// A did to expand in a follower graph walk.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct WalkNode {
+    /// dids it was reached through, root first: empty for the root
+    pub path: Vec<Did>,
+    /// followers read so far
+    pub read: usize,
+    /// whether all its followers, or as many as allowed, have been read
+    pub done: bool,
 }
 
 impl State {
-    pub fn new(modlist: ModList, cursor: Option<String>, jetstream_ts: Option<i64>) -> Self {
+    pub fn new(modlist: List, cursor: Option<String>, jetstream_ts: Option<i64>) -> Self {
         Self {
             modlist,
             cursor,
             jetstream_ts,
+            walk: None,
         }
     }
 
@@ -38,4 +61,208 @@ impl State {
     pub fn set_cursor(&mut self, cursor: String) {
         self.cursor = Some(cursor)
     }
+
+    /// Forgets the cursor, e.g. once a backfill is done so that the next one starts over.
+    pub fn clear_cursor(&mut self) {
+        self.cursor = None
+    }
+
+    pub fn walk(&self) -> Option<&WalkNode> {
+        self.walk.as_ref()
+    }
+}
+
+/// [`States`] shared between concurrent tasks, saved to a json file.
+///
+/// Runs sharing a file but not their progress, e.g. watching followers then
+/// follows of the same account, use different scopes.
+/// The lock is never held across an await point.
+#[derive(Debug)]
+pub struct StateStore {
+    path: PathBuf,
+    scope: Option<String>,
+    /// whether states saved without a scope are moved to this one
+    unscoped: bool,
+    states: Mutex<States>,
+}
+
+impl StateStore {
+    /// Loads states from path, starting from scratch if there's no file yet.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref().to_path_buf();
+        let states = match File::open(&path) {
+            Ok(r) => serde_json::from_reader(r)?,
+            Err(_) => States::new(),
+        };
+        Ok(Self {
+            path,
+            scope: None,
+            unscoped: false,
+            states: Mutex::new(states),
+        })
+    }
+
+    /// Only reads and writes the states of that scope.
+    pub fn with_scope(mut self, scope: impl Into<String>) -> Self {
+        self.scope = Some(scope.into());
+        self
+    }
+
+    /// Also reads states saved without a scope, by versions that didn't use one:
+    /// those of the same list are moved to this scope once read.
+    pub fn with_unscoped_fallback(mut self) -> Self {
+        self.unscoped = true;
+        self
+    }
+
+    fn key(&self, did: &Did) -> String {
+        match &self.scope {
+            Some(scope) => format!("{} {scope}", did.as_str()),
+            None => did.to_string(),
+        }
+    }
+
+    /// The did of a key of this scope.
+    fn did(&self, key: &str) -> Option<Did> {
+        let did = match &self.scope {
+            Some(scope) => key.strip_suffix(scope.as_str())?.strip_suffix(' ')?,
+            None => key,
+        };
+        did.parse().ok()
+    }
+
+    /// Gets (a copy of) the state of a did, creating it if needed.
+    pub fn get_or_insert(&self, did: &Did, modlist: List) -> State {
+        let key = self.key(did);
+        let mut states = self.states.lock().unwrap();
+        let unscoped = self.unscoped
+            && !states.contains_key(&key)
+            && states
+                .get(did.as_str())
+                .is_some_and(|s| s.modlist.uri() == modlist.uri());
+        if unscoped {
+            info!(
+                msg = "moving state to scope",
+                did = did.as_str(),
+                scope = self.scope
+            );
+            let state = states.remove(did.as_str()).unwrap();
+            states.insert(key.clone(), state);
+        }
+        states
+            .entry(key)
+            .or_insert(State::new(modlist, None, None))
+            .clone()
+    }
+
+    /// Updates the backfill cursor of a did, if it has a state.
+    pub fn set_cursor(&self, did: &Did, cursor: String) {
+        if let Some(state) = self.states.lock().unwrap().get_mut(&self.key(did)) {
+            state.set_cursor(cursor);
+        }
+    }
+
+    /// Forgets the backfill cursor of a did, if it has a state.
+    pub fn clear_cursor(&self, did: &Did) {
+        if let Some(state) = self.states.lock().unwrap().get_mut(&self.key(did)) {
+            state.clear_cursor();
+        }
+    }
+
+    /// Adds a did to expand in a graph walk, unless it already has a state.
+    pub fn insert_walk(&self, did: &Did, modlist: List, path: Vec<Did>) -> bool {
+        let key = self.key(did);
+        let mut states = self.states.lock().unwrap();
+        if states.contains_key(&key) {
+            return false;
+        }
+        let mut state = State::new(modlist, None, None);
+        state.walk = Some(WalkNode {
+            path,
+            ..Default::default()
+        });
+        states.insert(key, state);
+        true
+    }
+
+    /// Gets (copies of) the states of dids in a graph walk.
+    pub fn walk_nodes(&self) -> Vec<(Did, State)> {
+        self.states
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, state)| state.walk.is_some())
+            .filter_map(|(key, state)| Some((self.did(key)?, state.clone())))
+            .collect()
+    }
+
+    /// Updates the walk progress of a did, if it's in a graph walk.
+    pub fn set_walk_progress(&self, did: &Did, cursor: Option<String>, read: usize, done: bool) {
+        if let Some(state) = self.states.lock().unwrap().get_mut(&self.key(did)) {
+            if let Some(walk) = state.walk.as_mut() {
+                walk.read = read;
+                walk.done = done;
+                if cursor.is_some() {
+                    state.cursor = cursor;
+                }
+            }
+        }
+    }
+
+    /// Writes states to a temporary file then moves it over the previous one,
+    /// so that a crash while writing doesn't lose the states.
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let tmp = self.path.with_extension("tmp");
+        {
+            let states = self.states.lock().unwrap();
+            serde_json::to_writer(File::create(&tmp)?, &*states)?;
+        }
+        fs::rename(&tmp, &self.path)?;
+        info!(msg = "saved states", location = ?self.path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use atrium_api::types::string::Did;
+
+    use super::StateStore;
+    use crate::list::List;
+
+    #[test]
+    fn test_scopes() {
+        let did: Did = "did:plc:aaaaaaaaaaaaaaaaaaaaaaaa".parse().unwrap();
+        let followers = StateStore::load("/nonexistent/states.json").unwrap();
+        followers.get_or_insert(&did, List::new(String::new()));
+        followers.set_cursor(&did, "1".to_string());
+
+        let follows = StateStore {
+            scope: Some("follows".to_string()),
+            ..followers
+        };
+        assert!(follows.insert_walk(&did, List::new(String::new()), vec![]));
+        assert_eq!(follows.walk_nodes()[0].0, did);
+        assert_eq!(follows.walk_nodes()[0].1.cursor(), None);
+        assert_eq!(follows.states.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_unscoped_fallback() {
+        let did: Did = "did:plc:aaaaaaaaaaaaaaaaaaaaaaaa".parse().unwrap();
+        let (modlist, other) = (List::new("a".to_string()), List::new("b".to_string()));
+        let unscoped = StateStore::load("/nonexistent/states.json").unwrap();
+        unscoped.get_or_insert(&did, modlist.clone());
+        unscoped.set_cursor(&did, "1".to_string());
+
+        let scoped = unscoped.with_scope("followers b").with_unscoped_fallback();
+        // states of another list are left alone
+        assert_eq!(scoped.get_or_insert(&did, other).cursor(), None);
+        let scoped = StateStore {
+            scope: Some("followers a".to_string()),
+            ..scoped
+        };
+        assert_eq!(scoped.get_or_insert(&did, modlist).cursor(), Some("1"));
+        assert_eq!(scoped.states.lock().unwrap().len(), 2);
+    }
 }
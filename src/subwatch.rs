@@ -1,9 +1,16 @@
+use std::{collections::HashSet, fmt, time::Duration};
+
 use atrium_api::types::string::Did;
 use futures_core::Stream;
 use futures_util::StreamExt;
+use regex::RegexSet;
 use tokio::net::TcpStream;
-use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
-use tracing::info;
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{self, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+use tracing::{debug, info, warn};
 use url::Url;
 
 #[derive(Debug)]
@@ -15,7 +22,9 @@ pub enum Event {
 #[derive(Debug)]
 pub struct Follow {
     pub from: Did,
-    to: Did,
+    /// jetstream deletes only carry the record key: unknown for unfollows
+    to: Option<Did>,
+    rkey: String,
     event: Event,
     ts: i64,
 }
@@ -24,98 +33,796 @@ impl Follow {
     pub fn from(&self) -> &str {
         self.from.as_ref()
     }
+
+    pub fn to(&self) -> Option<&str> {
+        self.to.as_ref().map(|to| to.as_ref())
+    }
+
+    /// Record key of the follow record.
+    pub fn rkey(&self) -> &str {
+        &self.rkey
+    }
+
+    pub fn event(&self) -> &Event {
+        &self.event
+    }
+
+    pub fn ts(&self) -> i64 {
+        self.ts
+    }
 }
 
 impl TryFrom<serde_json::Value> for Follow {
-    type Error = serde_json::Error;
+    type Error = &'static str;
 
     fn try_from(value: serde_json::Value) -> Result<Self, Self::Error> {
-        let to = value
-            .get("commit")
-            .and_then(|v| v.get("record"))
-            .and_then(|v| v.get("subject"))
-            .and_then(|v| v.as_str())
-            .map(|v| Did::new(v.into()))
-            .unwrap()
-            .unwrap();
-
         let from = value
             .get("did")
             .and_then(|v| v.as_str())
-            .map(|v| Did::new(v.into()))
-            .unwrap()
-            .unwrap();
+            .ok_or("missing did")
+            .and_then(|v| Did::new(v.into()))?;
 
         let event = value
             .get("commit")
             .and_then(|v| v.get("operation"))
             .and_then(|v| v.as_str())
-            .expect("missing event");
+            .ok_or("missing event")?;
+
+        let rkey = value
+            .get("commit")
+            .and_then(|v| v.get("rkey"))
+            .and_then(|v| v.as_str())
+            .ok_or("missing rkey")?
+            .to_string();
 
         let ts = value
             .get("time_us")
             .and_then(|v| v.as_i64())
-            .expect("missing ts");
+            .ok_or("missing ts")?;
 
         let event = match event {
             "create" => Event::Follow,
             "delete" => Event::Unfollow,
-            _ => panic!("unsupported event"),
+            _ => return Err("unsupported event"),
+        };
+
+        let to = match event {
+            Event::Follow => value
+                .get("commit")
+                .and_then(|v| v.get("record"))
+                .and_then(|v| v.get("subject"))
+                .and_then(|v| v.as_str())
+                .map(|v| Did::new(v.into()))
+                .ok_or("missing subject")?
+                .ok(),
+            Event::Unfollow => None,
         };
 
         Ok(Self {
             from,
             to,
+            rkey,
             event,
             ts,
         })
     }
 }
 
-type FollowStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+const FOLLOW: &str = "app.bsky.graph.follow";
+const POST: &str = "app.bsky.feed.post";
+const LISTITEM: &str = "app.bsky.graph.listitem";
+const BLOCK: &str = "app.bsky.graph.block";
+
+/// Time to wait before connecting again once a connection failed.
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+type EventStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Which follow records are yielded.
+enum Filter {
+    /// follows of any of these accounts
+    Subjects(HashSet<Did>),
+    /// every follow, jetstream only sends those authored by the wanted dids
+    Authors,
+}
 
 pub struct SubWatcher {
-    watch_identifier: Did,
-    stream: FollowStream,
+    filter: Filter,
+    /// also yield unfollows, whatever their subject
+    deletes: bool,
+    stream: EventStream,
 }
 
-impl SubWatcher {
-    pub async fn new(jetstream: Url, watch_identifier: Did) -> Self {
-        let mut jetstream = jetstream.join("subscribe").unwrap();
-        jetstream
-            .query_pairs_mut()
-            .append_pair("wantedCollections", "app.bsky.graph.follow")
-            .append_pair("wantedDids", watch_identifier.as_str())
-            .finish();
-        // let jetstream2 = "wss://jetstream2.us-east.bsky.network/subscribe?wantedCollections=app.bsky.graph.follow";
-
-        info!(msg = "is eq", eq = jetstream.as_str());
-        info!(msg="opening stream", url=?jetstream.as_str());
-
-        let (stream, _) = connect_async(jetstream.as_str()).await.unwrap();
-        Self {
-            watch_identifier,
-            stream,
+async fn connect(
+    jetstream: Url,
+    collections: &[&str],
+    wanted_dids: &[Did],
+) -> Result<EventStream, tungstenite::Error> {
+    let mut jetstream = jetstream.join("subscribe").unwrap();
+    {
+        let mut query = jetstream.query_pairs_mut();
+        for collection in collections {
+            query.append_pair("wantedCollections", collection);
         }
+        // filters on the repo, that is the author of the record, not its subject
+        for did in wanted_dids {
+            query.append_pair("wantedDids", did.as_str());
+        }
+    }
+
+    info!(msg="opening stream", url=?jetstream.as_str());
+
+    let (stream, _) = connect_async(jetstream.as_str()).await?;
+    Ok(stream)
+}
+
+impl SubWatcher {
+    /// Watches follows of `watch_identifier`, from the whole network.
+    pub async fn new(jetstream: Url, watch_identifier: Did) -> Result<Self, tungstenite::Error> {
+        Ok(Self {
+            filter: Filter::Subjects(HashSet::from([watch_identifier])),
+            deletes: false,
+            stream: connect(jetstream, &[FOLLOW], &[]).await?,
+        })
+    }
+
+    /// Watches follows of any of `subjects`, from the whole network.
+    ///
+    /// Every unfollow is yielded too since their subject is unknown: callers
+    /// filter them on the follower.
+    pub async fn new_many(
+        jetstream: Url,
+        subjects: impl IntoIterator<Item = Did>,
+    ) -> Result<Self, tungstenite::Error> {
+        Ok(Self {
+            filter: Filter::Subjects(subjects.into_iter().collect()),
+            deletes: true,
+            stream: connect(jetstream, &[FOLLOW], &[]).await?,
+        })
+    }
+
+    /// Watches follows and unfollows made by `authors`.
+    pub async fn new_authored(jetstream: Url, authors: &[Did]) -> Result<Self, tungstenite::Error> {
+        Ok(Self {
+            filter: Filter::Authors,
+            deletes: true,
+            stream: connect(jetstream, &[FOLLOW], authors).await?,
+        })
     }
 
     pub async fn stream(self) -> impl Stream<Item = Follow> {
+        let filter = self.filter;
+        let deletes = self.deletes;
+        self.stream.filter_map(move |item| {
+            let item: serde_json::Value = match item {
+                Ok(Message::Text(text)) => serde_json::from_str(&text).unwrap_or_default(),
+                Ok(_) => serde_json::Value::Null,
+                Err(e) => {
+                    warn!(msg = "follow stream error", err = %e);
+                    serde_json::Value::Null
+                }
+            };
+            let subject = item["commit"]["record"]["subject"]
+                .as_str()
+                .unwrap_or("none");
+            let operation = item["commit"]["operation"].as_str();
+            let delete = operation == Some("delete");
+
+            let wanted = match &filter {
+                // identity and account events carry no commit, follows are never updated
+                _ if operation.is_none() || operation == Some("update") => false,
+                _ if delete => deletes,
+                Filter::Subjects(subjects) => subjects.iter().any(|s| s.as_str() == subject),
+                Filter::Authors => true,
+            };
+            let follow = match wanted {
+                true => {
+                    debug!(item=?item);
+                    match item.try_into() {
+                        Ok(follow) => Some(follow),
+                        Err(e) => {
+                            warn!(msg = "could not read follow, skipping", err = e);
+                            None
+                        }
+                    }
+                }
+                false => None,
+            };
+            async move { follow }
+        })
+    }
+}
+
+/// Part of a post that matched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Text,
+    Tag,
+    Link,
+    AltText,
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Field::Text => write!(f, "text"),
+            Field::Tag => write!(f, "tag"),
+            Field::Link => write!(f, "link"),
+            Field::AltText => write!(f, "alt text"),
+        }
+    }
+}
+
+/// Regex sets matched against new posts.
+///
+/// Text patterns also apply to image and video alt texts when enabled, tag
+/// patterns to hashtags (without `#`, from facets and the `tags` field), link
+/// patterns to link facets.
+#[derive(Debug, Clone)]
+pub struct PostMatcher {
+    text: RegexSet,
+    tags: RegexSet,
+    links: RegexSet,
+    alt_text: bool,
+}
+
+/// A post that matched a [`PostMatcher`]: its author and uri, as provenance.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PostMatch {
+    pub author: Did,
+    pub uri: String,
+    pub field: Field,
+    /// the pattern that matched
+    pub pattern: String,
+}
+
+fn strs(value: &serde_json::Value) -> impl Iterator<Item = &str> {
+    value
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|v| v.as_str())
+}
+
+impl PostMatcher {
+    pub fn new(text: &[String], tags: &[String], links: &[String]) -> Result<Self, regex::Error> {
+        Ok(Self {
+            text: RegexSet::new(text)?,
+            tags: RegexSet::new(tags)?,
+            links: RegexSet::new(links)?,
+            alt_text: false,
+        })
+    }
+
+    /// Also matches text patterns against alt texts.
+    pub fn with_alt_text(mut self) -> Self {
+        self.alt_text = true;
+        self
+    }
+
+    fn find<'a>(
+        set: &RegexSet,
+        field: Field,
+        mut values: impl Iterator<Item = &'a str>,
+    ) -> Option<(Field, String)> {
+        values.find_map(|v| {
+            let i = set.matches(v).into_iter().next()?;
+            Some((field, set.patterns()[i].clone()))
+        })
+    }
+
+    /// Matches the post created by a jetstream commit event, if any.
+    pub fn matches(&self, event: &serde_json::Value) -> Option<PostMatch> {
+        let commit = &event["commit"];
+        if commit["operation"].as_str() != Some("create")
+            || commit["collection"].as_str() != Some(POST)
+        {
+            return None;
+        }
+        let record = &commit["record"];
+        let features = record["facets"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .flat_map(|f| f["features"].as_array().into_iter().flatten());
+        let facet = |kind: &'static str, key: &'static str| {
+            features
+                .clone()
+                .filter(move |f| f["$type"].as_str() == Some(kind))
+                .filter_map(move |f| f[key].as_str())
+        };
+        let embed = &record["embed"];
+        let alt_texts = embed["images"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|i| i["alt"].as_str())
+            .chain(
+                embed["media"]["images"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|i| i["alt"].as_str()),
+            )
+            .chain(embed["alt"].as_str())
+            .chain(embed["media"]["alt"].as_str());
+
+        let (field, pattern) =
+            Self::find(&self.text, Field::Text, record["text"].as_str().into_iter())
+                .or_else(|| {
+                    let tags =
+                        facet("app.bsky.richtext.facet#tag", "tag").chain(strs(&record["tags"]));
+                    Self::find(&self.tags, Field::Tag, tags)
+                })
+                .or_else(|| {
+                    Self::find(
+                        &self.links,
+                        Field::Link,
+                        facet("app.bsky.richtext.facet#link", "uri"),
+                    )
+                })
+                .or_else(|| match self.alt_text {
+                    true => Self::find(&self.text, Field::AltText, alt_texts),
+                    false => None,
+                })?;
+
+        let author: Did = event["did"].as_str()?.parse().ok()?;
+        let uri = format!(
+            "at://{}/app.bsky.feed.post/{}",
+            author.as_str(),
+            commit["rkey"].as_str()?
+        );
+        Some(PostMatch {
+            author,
+            uri,
+            field,
+            pattern,
+        })
+    }
+}
+
+/// Watches new posts of the whole network for those matching a [`PostMatcher`].
+pub struct PostWatcher {
+    matcher: PostMatcher,
+    stream: EventStream,
+}
+
+impl PostWatcher {
+    pub async fn new(jetstream: Url, matcher: PostMatcher) -> Result<Self, tungstenite::Error> {
+        Ok(Self {
+            matcher,
+            stream: connect(jetstream, &[POST], &[]).await?,
+        })
+    }
+
+    pub async fn stream(self) -> impl Stream<Item = PostMatch> {
+        let matcher = self.matcher;
+        self.stream.filter_map(move |item| {
+            let post = match item {
+                Ok(Message::Text(text)) => serde_json::from_str(&text)
+                    .ok()
+                    .and_then(|item: serde_json::Value| matcher.matches(&item)),
+                Ok(_) => None,
+                Err(e) => {
+                    warn!(msg = "post stream error", err = %e);
+                    None
+                }
+            };
+            async move { post }
+        })
+    }
+}
+
+/// How a post involves a protected account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interaction {
+    /// replies to one of its posts, or in one of its threads
+    Reply,
+    Mention,
+}
+
+impl fmt::Display for Interaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Interaction::Reply => write!(f, "reply"),
+            Interaction::Mention => write!(f, "mention"),
+        }
+    }
+}
+
+/// A post replying to or mentioning a protected account.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PostInteraction {
+    pub author: Did,
+    pub uri: String,
+    pub kind: Interaction,
+    /// jetstream time, in microseconds
+    pub ts: i64,
+}
+
+impl PostInteraction {
+    /// Reads a jetstream commit event creating a post that involves `protected`.
+    ///
+    /// Its own posts, e.g. replies in its threads, are ignored.
+    pub fn from_event(event: &serde_json::Value, protected: &Did) -> Option<Self> {
+        let commit = &event["commit"];
+        if commit["operation"].as_str() != Some("create")
+            || commit["collection"].as_str() != Some(POST)
+        {
+            return None;
+        }
+        let author: Did = event["did"].as_str()?.parse().ok()?;
+        if author == *protected {
+            return None;
+        }
+        let record = &commit["record"];
+        let prefix = format!("at://{}/", protected.as_str());
+        let reply = &record["reply"];
+        let replied = [&reply["parent"]["uri"], &reply["root"]["uri"]]
+            .iter()
+            .any(|uri| uri.as_str().is_some_and(|uri| uri.starts_with(&prefix)));
+        let mentioned = || {
+            record["facets"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .flat_map(|f| f["features"].as_array().into_iter().flatten())
+                .any(|f| {
+                    f["$type"].as_str() == Some("app.bsky.richtext.facet#mention")
+                        && f["did"].as_str() == Some(protected.as_str())
+                })
+        };
+        let kind = match replied {
+            true => Interaction::Reply,
+            false if mentioned() => Interaction::Mention,
+            false => return None,
+        };
+
+        let uri = format!(
+            "at://{}/app.bsky.feed.post/{}",
+            author.as_str(),
+            commit["rkey"].as_str()?
+        );
+        Some(Self {
+            author,
+            uri,
+            kind,
+            ts: event["time_us"].as_i64()?,
+        })
+    }
+}
+
+/// Watches new posts of the whole network for replies to and mentions of an account.
+pub struct InteractionWatcher {
+    protected: Did,
+    stream: EventStream,
+}
+
+impl InteractionWatcher {
+    pub async fn new(jetstream: Url, protected: Did) -> Result<Self, tungstenite::Error> {
+        Ok(Self {
+            protected,
+            stream: connect(jetstream, &[POST], &[]).await?,
+        })
+    }
+
+    pub async fn stream(self) -> impl Stream<Item = PostInteraction> {
+        let protected = self.protected;
+        self.stream.filter_map(move |item| {
+            let post = match item {
+                Ok(Message::Text(text)) => {
+                    serde_json::from_str(&text)
+                        .ok()
+                        .and_then(|item: serde_json::Value| {
+                            PostInteraction::from_event(&item, &protected)
+                        })
+                }
+                Ok(_) => None,
+                Err(e) => {
+                    warn!(msg = "post stream error", err = %e);
+                    None
+                }
+            };
+            async move { post }
+        })
+    }
+}
+
+/// Change to a list item record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListItemEvent {
+    Added {
+        list: String,
+        subject: Did,
+        rkey: String,
+    },
+    /// jetstream deletes only carry the record key
+    Removed { rkey: String },
+}
+
+impl ListItemEvent {
+    /// Reads a jetstream commit event on a list item.
+    pub fn from_event(event: &serde_json::Value) -> Option<Self> {
+        let commit = &event["commit"];
+        if commit["collection"].as_str() != Some(LISTITEM) {
+            return None;
+        }
+        let rkey = commit["rkey"].as_str()?.to_string();
+        match commit["operation"].as_str()? {
+            "create" => Some(ListItemEvent::Added {
+                list: commit["record"]["list"].as_str()?.to_string(),
+                subject: commit["record"]["subject"].as_str()?.parse().ok()?,
+                rkey,
+            }),
+            "delete" => Some(ListItemEvent::Removed { rkey }),
+            _ => None,
+        }
+    }
+}
+
+/// Watches list items created or deleted by the owner of some lists.
+pub struct ListItemWatcher {
+    stream: EventStream,
+}
+
+impl ListItemWatcher {
+    pub async fn new(jetstream: Url, owner: &Did) -> Result<Self, tungstenite::Error> {
+        Ok(Self {
+            stream: connect(jetstream, &[LISTITEM], std::slice::from_ref(owner)).await?,
+        })
+    }
+
+    pub async fn stream(self) -> impl Stream<Item = ListItemEvent> {
+        self.stream.filter_map(|item| {
+            let event = match item {
+                Ok(Message::Text(text)) => serde_json::from_str(&text)
+                    .ok()
+                    .and_then(|item: serde_json::Value| ListItemEvent::from_event(&item)),
+                Ok(_) => None,
+                Err(e) => {
+                    warn!(msg = "list item stream error", err = %e);
+                    None
+                }
+            };
+            async move { event }
+        })
+    }
+}
+
+/// How an account targets a protected one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Targeting {
+    Block,
+    /// added it to one of its lists
+    ListItem {
+        list: String,
+    },
+}
+
+impl fmt::Display for Targeting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Targeting::Block => write!(f, "block"),
+            Targeting::ListItem { list } => write!(f, "list item on {list}"),
+        }
+    }
+}
+
+/// A block or list item record whose subject is a protected account.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetEvent {
+    pub author: Did,
+    pub subject: Did,
+    pub targeting: Targeting,
+    pub ts: i64,
+}
+
+impl TargetEvent {
+    /// Reads a jetstream commit event creating a block or list item of one of `protected`.
+    ///
+    /// Deletes carry no subject, they're ignored.
+    pub fn from_event(event: &serde_json::Value, protected: &HashSet<Did>) -> Option<Self> {
+        let commit = &event["commit"];
+        if commit["operation"].as_str() != Some("create") {
+            return None;
+        }
+        let record = &commit["record"];
+        let subject: Did = record["subject"].as_str()?.parse().ok()?;
+        if !protected.contains(&subject) {
+            return None;
+        }
+        let targeting = match commit["collection"].as_str()? {
+            BLOCK => Targeting::Block,
+            LISTITEM => Targeting::ListItem {
+                list: record["list"].as_str()?.to_string(),
+            },
+            _ => return None,
+        };
+        Some(Self {
+            author: event["did"].as_str()?.parse().ok()?,
+            subject,
+            targeting,
+            ts: event["time_us"].as_i64()?,
+        })
+    }
+}
+
+/// Watches blocks and list items of the whole network for those targeting protected accounts.
+pub struct TargetWatcher {
+    protected: HashSet<Did>,
+    stream: EventStream,
+}
+
+impl TargetWatcher {
+    pub async fn new(
+        jetstream: Url,
+        protected: impl IntoIterator<Item = Did>,
+    ) -> Result<Self, tungstenite::Error> {
+        Ok(Self {
+            protected: protected.into_iter().collect(),
+            stream: connect(jetstream, &[BLOCK, LISTITEM], &[]).await?,
+        })
+    }
+
+    pub async fn stream(self) -> impl Stream<Item = TargetEvent> {
+        let protected = self.protected;
         self.stream.filter_map(move |item| {
-            let watch_identifier = self.watch_identifier.clone();
-            async move {
-                let item = item.unwrap();
-                let item: serde_json::Value = serde_json::from_slice(&item.into_data()).unwrap();
-                info!(item=?item);
-                let subject = &item["commit"]["record"]["subject"]
-                    .as_str()
-                    .unwrap_or("none");
-
-                if watch_identifier.as_str() == *subject {
-                    Some(item.try_into().unwrap())
-                } else {
+            let event = match item {
+                Ok(Message::Text(text)) => serde_json::from_str(&text)
+                    .ok()
+                    .and_then(|item: serde_json::Value| TargetEvent::from_event(&item, &protected)),
+                Ok(_) => None,
+                Err(e) => {
+                    warn!(msg = "block and list item stream error", err = %e);
                     None
                 }
+            };
+            async move { event }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use atrium_api::types::string::Did;
+    use serde_json::json;
+
+    use std::collections::HashSet;
+
+    use super::{Field, Follow, Interaction, PostInteraction, PostMatcher, TargetEvent, Targeting};
+
+    const AUTHOR: &str = "did:plc:aaaaaaaaaaaaaaaaaaaaaaaa";
+
+    /// A jetstream commit event.
+    fn event(
+        did: &str,
+        operation: &str,
+        collection: &str,
+        record: serde_json::Value,
+    ) -> serde_json::Value {
+        json!({
+            "did": did,
+            "time_us": 1725911162329308i64,
+            "kind": "commit",
+            "commit": {
+                "operation": operation,
+                "collection": collection,
+                "rkey": "3kabc",
+                "record": record,
             }
         })
     }
+
+    #[test]
+    fn test_follow() {
+        let follow = |operation, record| event(AUTHOR, operation, "app.bsky.graph.follow", record);
+        let subject = json!({"subject": "did:plc:pppppppppppppppppppppppp"});
+
+        let f = Follow::try_from(follow("create", subject.clone())).unwrap();
+        assert_eq!(f.to(), Some("did:plc:pppppppppppppppppppppppp"));
+        assert_eq!(f.rkey(), "3kabc");
+        assert!(Follow::try_from(follow("update", subject)).is_err());
+        assert!(Follow::try_from(follow("create", json!({}))).is_err());
+        assert!(Follow::try_from(json!({"kind": "identity"})).is_err());
+    }
+
+    #[test]
+    fn test_post_matcher() {
+        let post = |record| event(AUTHOR, "create", "app.bsky.feed.post", record);
+        let matcher = PostMatcher::new(
+            &["(?i)\\bspam\\b".to_string()],
+            &["^nsfw$".to_string()],
+            &["example\\.com".to_string()],
+        )
+        .unwrap();
+
+        let m = matcher
+            .matches(&post(json!({"text": "buy SPAM now"})))
+            .unwrap();
+        assert_eq!(m.field, Field::Text);
+        assert_eq!(
+            m.uri,
+            "at://did:plc:aaaaaaaaaaaaaaaaaaaaaaaa/app.bsky.feed.post/3kabc"
+        );
+
+        let facets = json!({"text": "hi", "facets": [{"features": [
+            {"$type": "app.bsky.richtext.facet#link", "uri": "https://example.com/x"}
+        ]}]});
+        assert_eq!(matcher.matches(&post(facets)).unwrap().field, Field::Link);
+
+        let tags = json!({"text": "hi", "tags": ["nsfw"]});
+        assert_eq!(matcher.matches(&post(tags)).unwrap().field, Field::Tag);
+
+        let alt = json!({"text": "hi", "embed": {"images": [{"alt": "spam"}]}});
+        assert!(matcher.matches(&post(alt.clone())).is_none());
+        let m = matcher.with_alt_text().matches(&post(alt)).unwrap();
+        assert_eq!(m.field, Field::AltText);
+    }
+
+    #[test]
+    fn test_post_interaction() {
+        let post = |did, record| event(did, "create", "app.bsky.feed.post", record);
+        let protected: Did = "did:plc:pppppppppppppppppppppppp".parse().unwrap();
+        let other = AUTHOR;
+        let reply = json!({"text": "hi", "reply": {
+            "root": {"uri": "at://did:plc:pppppppppppppppppppppppp/app.bsky.feed.post/1"},
+            "parent": {"uri": "at://did:plc:bbbbbbbbbbbbbbbbbbbbbbbb/app.bsky.feed.post/2"},
+        }});
+        let mention = json!({"text": "@p hi", "facets": [{"features": [
+            {"$type": "app.bsky.richtext.facet#mention", "did": "did:plc:pppppppppppppppppppppppp"}
+        ]}]});
+
+        let i = PostInteraction::from_event(&post(other, reply.clone()), &protected).unwrap();
+        assert_eq!(i.kind, Interaction::Reply);
+        assert_eq!(i.ts, 1725911162329308);
+        let i = PostInteraction::from_event(&post(other, mention), &protected).unwrap();
+        assert_eq!(i.kind, Interaction::Mention);
+        assert!(
+            PostInteraction::from_event(&post(other, json!({"text": "hi"})), &protected).is_none()
+        );
+        // its own replies in its threads
+        assert!(
+            PostInteraction::from_event(&post(protected.as_str(), reply), &protected).is_none()
+        );
+    }
+
+    #[test]
+    fn test_target_event() {
+        let protected: Did = "did:plc:pppppppppppppppppppppppp".parse().unwrap();
+        let protected = HashSet::from([protected]);
+        let list = "at://did:plc:aaaaaaaaaaaaaaaaaaaaaaaa/app.bsky.graph.list/3kl";
+
+        let block = json!({"subject": "did:plc:pppppppppppppppppppppppp"});
+        let e = TargetEvent::from_event(
+            &event(AUTHOR, "create", "app.bsky.graph.block", block),
+            &protected,
+        )
+        .unwrap();
+        assert_eq!(e.targeting, Targeting::Block);
+        assert_eq!(e.author.as_str(), AUTHOR);
+
+        let item = json!({"subject": "did:plc:pppppppppppppppppppppppp", "list": list});
+        let e = TargetEvent::from_event(
+            &event(AUTHOR, "create", "app.bsky.graph.listitem", item.clone()),
+            &protected,
+        )
+        .unwrap();
+        assert_eq!(
+            e.targeting,
+            Targeting::ListItem {
+                list: list.to_string()
+            }
+        );
+        assert!(TargetEvent::from_event(
+            &event(AUTHOR, "delete", "app.bsky.graph.listitem", item),
+            &protected
+        )
+        .is_none());
+        let other = json!({"subject": "did:plc:bbbbbbbbbbbbbbbbbbbbbbbb"});
+        assert!(TargetEvent::from_event(
+            &event(AUTHOR, "create", "app.bsky.graph.block", other),
+            &protected
+        )
+        .is_none());
+    }
 }
@@ -0,0 +1,235 @@
+//! N-of-M rules over watched accounts
+//!
+//! Following one watched account is a weak signal, following several accounts
+//! of a known cluster a strong one. [`Scores`] tracks which watched accounts
+//! each did follows, and tells when its score crosses the [`Rule`] threshold,
+//! upward (add to the list) or downward (remove from it).
+//!
+//! Scores are saved next to the states so that a resumed backfill doesn't lose
+//! the follows read before its cursor.
+
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+};
+
+use atrium_api::types::string::Did;
+use tracing::info;
+
+/// A watched account given on the command line: `account` or `account=weight`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watched {
+    pub account: String,
+    pub weight: f64,
+}
+
+impl FromStr for Watched {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.rsplit_once('=') {
+            Some((account, weight)) => Ok(Self {
+                account: account.to_string(),
+                weight: weight
+                    .parse()
+                    .map_err(|_| format!("invalid weight: {weight}"))?,
+            }),
+            None => Ok(Self {
+                account: s.to_string(),
+                weight: 1.0,
+            }),
+        }
+    }
+}
+
+/// Weighted watched accounts and the score to reach.
+///
+/// With all weights at 1, a threshold of N means "follows N of the M accounts".
+#[derive(Debug, Clone)]
+pub struct Rule {
+    weights: HashMap<Did, f64>,
+    threshold: f64,
+}
+
+impl Rule {
+    pub fn new(threshold: f64) -> Self {
+        Self {
+            weights: HashMap::new(),
+            threshold,
+        }
+    }
+
+    pub fn watch(mut self, did: Did, weight: f64) -> Self {
+        self.weights.insert(did, weight);
+        self
+    }
+
+    pub fn accounts(&self) -> impl Iterator<Item = &Did> {
+        self.weights.keys()
+    }
+
+    pub fn is_watched(&self, did: &Did) -> bool {
+        self.weights.contains_key(did)
+    }
+
+    pub fn score<'a>(&self, followed: impl IntoIterator<Item = &'a Did>) -> f64 {
+        followed
+            .into_iter()
+            .filter_map(|did| self.weights.get(did))
+            .sum()
+    }
+
+    pub fn matches<'a>(&self, followed: impl IntoIterator<Item = &'a Did>) -> bool {
+        self.score(followed) >= self.threshold
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Add,
+    Remove,
+}
+
+/// Watched accounts followed by each did, shared between concurrent tasks.
+#[derive(Debug)]
+pub struct Scores {
+    rule: Rule,
+    path: PathBuf,
+    follows: Mutex<HashMap<Did, HashSet<Did>>>,
+}
+
+impl Scores {
+    /// Loads follows from path, starting from scratch if there's no file yet.
+    pub fn load(path: impl AsRef<Path>, rule: Rule) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref().to_path_buf();
+        let follows = match File::open(&path) {
+            Ok(r) => serde_json::from_reader(r)?,
+            Err(_) => HashMap::new(),
+        };
+        Ok(Self {
+            rule,
+            path,
+            follows: Mutex::new(follows),
+        })
+    }
+
+    pub fn rule(&self) -> &Rule {
+        &self.rule
+    }
+
+    /// Whether some watched account is known to be followed by did.
+    pub fn is_tracked(&self, did: &Did) -> bool {
+        self.follows.lock().unwrap().contains_key(did)
+    }
+
+    /// Dids whose score reaches the threshold.
+    pub fn matching(&self) -> Vec<Did> {
+        self.follows
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, followed)| self.rule.matches(followed.iter()))
+            .map(|(did, _)| did.clone())
+            .collect()
+    }
+
+    fn update(&self, follower: &Did, f: impl FnOnce(&mut HashSet<Did>)) -> Option<Change> {
+        let mut follows = self.follows.lock().unwrap();
+        let followed = follows.entry(follower.clone()).or_default();
+        let before = self.rule.matches(followed.iter());
+        f(followed);
+        followed.retain(|did| self.rule.is_watched(did));
+        let after = self.rule.matches(followed.iter());
+        if followed.is_empty() {
+            follows.remove(follower);
+        }
+        match (before, after) {
+            (false, true) => Some(Change::Add),
+            (true, false) => Some(Change::Remove),
+            _ => None,
+        }
+    }
+
+    /// Records that follower follows watched.
+    pub fn follow(&self, follower: &Did, watched: &Did) -> Option<Change> {
+        self.update(follower, |followed| {
+            followed.insert(watched.clone());
+        })
+    }
+
+    /// Records that follower doesn't follow watched anymore.
+    pub fn unfollow(&self, follower: &Did, watched: &Did) -> Option<Change> {
+        self.update(follower, |followed| {
+            followed.remove(watched);
+        })
+    }
+
+    /// Replaces the watched accounts followed by follower.
+    pub fn set(&self, follower: &Did, watched: HashSet<Did>) -> Option<Change> {
+        self.update(follower, |followed| *followed = watched)
+    }
+
+    /// Writes follows to a temporary file then moves it over the previous one.
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let tmp = self.path.with_extension("tmp");
+        {
+            let follows = self.follows.lock().unwrap();
+            serde_json::to_writer(File::create(&tmp)?, &*follows)?;
+        }
+        fs::rename(&tmp, &self.path)?;
+        info!(msg = "saved scores", location = ?self.path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use atrium_api::types::string::Did;
+
+    use super::{Change, Rule, Scores, Watched};
+
+    #[test]
+    fn test_scores() {
+        let a: Did = "did:plc:aaaaaaaaaaaaaaaaaaaaaaaa".parse().unwrap();
+        let b: Did = "did:plc:bbbbbbbbbbbbbbbbbbbbbbbb".parse().unwrap();
+        let c: Did = "did:plc:cccccccccccccccccccccccc".parse().unwrap();
+        let user: Did = "did:plc:uuuuuuuuuuuuuuuuuuuuuuuu".parse().unwrap();
+
+        // 2 of 3, with c counting double
+        let rule = Rule::new(2.0)
+            .watch(a.clone(), 1.0)
+            .watch(b.clone(), 1.0)
+            .watch(c.clone(), 2.0);
+        let scores = Scores::load("/nonexistent/scores.json", rule).unwrap();
+
+        assert_eq!(scores.follow(&user, &a), None);
+        assert_eq!(scores.follow(&user, &a), None);
+        assert_eq!(scores.follow(&user, &b), Some(Change::Add));
+        assert_eq!(scores.follow(&user, &c), None);
+        assert_eq!(scores.unfollow(&user, &a), None);
+        assert_eq!(
+            scores.set(&user, HashSet::from([a.clone()])),
+            Some(Change::Remove)
+        );
+        assert_eq!(scores.set(&user, HashSet::from([c])), Some(Change::Add));
+        assert_eq!(scores.matching(), vec![user.clone()]);
+        assert_eq!(scores.set(&user, HashSet::new()), Some(Change::Remove));
+        assert!(!scores.is_tracked(&user));
+    }
+
+    #[test]
+    fn test_watched() {
+        let w: Watched = "foo.bsky.social=2.5".parse().unwrap();
+        assert_eq!(w.account, "foo.bsky.social");
+        assert_eq!(w.weight, 2.5);
+        let w: Watched = "did:plc:aaaaaaaaaaaaaaaaaaaaaaaa".parse().unwrap();
+        assert_eq!(w.weight, 1.0);
+        assert!("foo=bar".parse::<Watched>().is_err());
+    }
+}
@@ -0,0 +1,537 @@
+//! Prioritized write queue in front of a [`List`](crate::list::List), or any other [`Output`]
+//!
+//! All sources share the same rate limiter: without a queue, a large backfill
+//! starves live follow events for hours. Sources send dids through a [`Writer`]
+//! with a [`Priority`], and a single [`WriteQueue`] writes them, always picking
+//! live ones first, then reconcile, then backfill.
+//!
+//! Queues are bounded: a source waits when its queue is full.
+//!
+//! Exempted dids (see [`Exemptions`]) are checked right before writing and skipped.
+//! Writes failing on rate limits, server or network errors are retried with
+//! backoff. Dids whose write still fails are forgotten from the sources'
+//! [`SeenSet`], and the next flush of their class fails so that sources don't
+//! checkpoint past them.
+
+use std::{
+    error::Error,
+    fmt,
+    future::{self, Future},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use atrium_api::{agent::store::SessionStore, types::string::Did, xrpc::XrpcClient};
+use bsky_sdk::BskyAgent;
+use futures_core::Stream;
+use futures_util::{pin_mut, StreamExt};
+use tokio::{
+    select, signal,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{
+    dedup::SeenSet,
+    exempt::Exemptions,
+    followers::PageError,
+    output::Output,
+    session::{self, relogin, Credentials},
+};
+
+/// Log metrics every N writes.
+const LOG_EVERY: usize = 100;
+
+/// Retries of a write failing on a transient error, before giving up.
+const MAX_RETRIES: u32 = 5;
+
+/// Delay before the first retry, doubled on each of the next ones.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    Live,
+    Reconcile,
+    Backfill,
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Priority::Live => write!(f, "live"),
+            Priority::Reconcile => write!(f, "reconcile"),
+            Priority::Backfill => write!(f, "backfill"),
+        }
+    }
+}
+
+enum Write {
+    Add(Did),
+    /// written in as few requests as possible, see [`Output::add_batch`]
+    AddBatch(Vec<Did>),
+    Remove(Did),
+    /// Answered once every previous write of the same class is done, with the
+    /// number of writes given up since the previous flush.
+    Flush(oneshot::Sender<usize>),
+}
+
+impl Write {
+    fn dids(&self) -> &[Did] {
+        match self {
+            Write::Add(did) | Write::Remove(did) => std::slice::from_ref(did),
+            Write::AddBatch(dids) => dids,
+            Write::Flush(_) => &[],
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ClassMetrics {
+    queued: AtomicUsize,
+    written: AtomicUsize,
+    failed: AtomicUsize,
+    exempted: AtomicUsize,
+    removed: AtomicUsize,
+    /// failed writes not reported by a flush yet
+    unflushed: AtomicUsize,
+}
+
+/// Counters of a priority class.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClassSnapshot {
+    pub queued: usize,
+    pub written: usize,
+    pub failed: usize,
+    pub exempted: usize,
+    pub removed: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    live: ClassMetrics,
+    reconcile: ClassMetrics,
+    backfill: ClassMetrics,
+}
+
+impl Metrics {
+    fn class(&self, priority: Priority) -> &ClassMetrics {
+        match priority {
+            Priority::Live => &self.live,
+            Priority::Reconcile => &self.reconcile,
+            Priority::Backfill => &self.backfill,
+        }
+    }
+
+    pub fn snapshot(&self, priority: Priority) -> ClassSnapshot {
+        let class = self.class(priority);
+        ClassSnapshot {
+            queued: class.queued.load(Ordering::Relaxed),
+            written: class.written.load(Ordering::Relaxed),
+            failed: class.failed.load(Ordering::Relaxed),
+            exempted: class.exempted.load(Ordering::Relaxed),
+            removed: class.removed.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn log(&self) {
+        for priority in [Priority::Live, Priority::Reconcile, Priority::Backfill] {
+            let s = self.snapshot(priority);
+            info!(
+                msg = "write queue",
+                class = %priority,
+                queued = s.queued,
+                written = s.written,
+                failed = s.failed,
+                exempted = s.exempted,
+                removed = s.removed
+            );
+        }
+    }
+}
+
+/// Sending side of the queue, cheap to clone.
+#[derive(Clone)]
+pub struct Writer {
+    live: mpsc::Sender<Write>,
+    reconcile: mpsc::Sender<Write>,
+    backfill: mpsc::Sender<Write>,
+    metrics: Arc<Metrics>,
+}
+
+/// Writing side of the queue, see [`WriteQueue::run`].
+pub struct WriteQueue {
+    live: mpsc::Receiver<Write>,
+    reconcile: mpsc::Receiver<Write>,
+    backfill: mpsc::Receiver<Write>,
+    metrics: Arc<Metrics>,
+    /// write that failed on an expired session, retried on next run
+    pending: Option<(Priority, Write)>,
+    exemptions: Option<Arc<Exemptions>>,
+    refresh: Duration,
+    seen: Option<SeenSet>,
+}
+
+/// Creates a queue holding up to `capacity` dids per priority class.
+pub fn channel(capacity: usize) -> (Writer, WriteQueue) {
+    let (live_tx, live_rx) = mpsc::channel(capacity);
+    let (reconcile_tx, reconcile_rx) = mpsc::channel(capacity);
+    let (backfill_tx, backfill_rx) = mpsc::channel(capacity);
+    let metrics = Arc::new(Metrics::default());
+    (
+        Writer {
+            live: live_tx,
+            reconcile: reconcile_tx,
+            backfill: backfill_tx,
+            metrics: metrics.clone(),
+        },
+        WriteQueue {
+            live: live_rx,
+            reconcile: reconcile_rx,
+            backfill: backfill_rx,
+            metrics,
+            pending: None,
+            exemptions: None,
+            refresh: Duration::MAX,
+            seen: None,
+        },
+    )
+}
+
+impl Writer {
+    fn sender(&self, priority: Priority) -> &mpsc::Sender<Write> {
+        match priority {
+            Priority::Live => &self.live,
+            Priority::Reconcile => &self.reconcile,
+            Priority::Backfill => &self.backfill,
+        }
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    async fn send(&self, priority: Priority, write: Write) -> Result<(), Box<dyn Error>> {
+        // counted before sending so that the queue never decrements it first
+        let queued = &self.metrics.class(priority).queued;
+        let nb = write.dids().len();
+        queued.fetch_add(nb, Ordering::Relaxed);
+        if self.sender(priority).send(write).await.is_err() {
+            queued.fetch_sub(nb, Ordering::Relaxed);
+            return Err("write queue closed".into());
+        }
+        Ok(())
+    }
+
+    /// Queues a did, waiting if the queue of this class is full.
+    pub async fn add(&self, priority: Priority, did: Did) -> Result<(), Box<dyn Error>> {
+        self.send(priority, Write::Add(did)).await
+    }
+
+    /// Queues dids to be written together, e.g. in a single `applyWrites` call.
+    pub async fn add_batch(
+        &self,
+        priority: Priority,
+        dids: Vec<Did>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.send(priority, Write::AddBatch(dids)).await
+    }
+
+    /// Queues the removal of a did from the list.
+    pub async fn remove(&self, priority: Priority, did: Did) -> Result<(), Box<dyn Error>> {
+        self.send(priority, Write::Remove(did)).await
+    }
+
+    /// Waits until every did queued so far in this class has been written.
+    ///
+    /// Fails if some writes had to be given up since the previous flush.
+    pub async fn flush(&self, priority: Priority) -> Result<(), Box<dyn Error>> {
+        let (tx, rx) = oneshot::channel();
+        self.sender(priority)
+            .send(Write::Flush(tx))
+            .await
+            .map_err(|_| "write queue closed")?;
+        match rx.await.map_err(|_| "write queue stopped")? {
+            0 => Ok(()),
+            failed => Err(format!("{failed} {priority} writes failed").into()),
+        }
+    }
+
+    /// Queues a stream of dids.
+    /// Calls `checkpoint` with the cursor to resume from each time a page has been fully written,
+    /// like [`List::add_stream_checkpointed`](crate::list::List::add_stream_checkpointed).
+    pub async fn add_stream_checkpointed(
+        &self,
+        priority: Priority,
+        dids: impl Stream<Item = Result<(Did, Option<String>), PageError>>,
+        mut checkpoint: impl FnMut(&str) -> Result<(), Box<dyn Error>>,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        pin_mut!(dids);
+        let mut last_cursor: Option<String> = None;
+        while let Some(item) = dids.next().await {
+            // the current page may be partly written, it's read again on resume
+            let (did, cursor) = item?;
+            if let Some(c) = cursor {
+                if let Some(done) = last_cursor.as_deref().filter(|l| *l != c) {
+                    self.flush(priority).await?;
+                    checkpoint(done)?;
+                }
+                last_cursor = Some(c);
+            }
+            self.add(priority, did).await?;
+        }
+
+        self.flush(priority).await?;
+        match &last_cursor {
+            Some(c) => checkpoint(c)?,
+            None => warn!(msg = "no last cursor found!"),
+        }
+        Ok(last_cursor)
+    }
+}
+
+impl WriteQueue {
+    /// Skips dids exempted at the time they are written.
+    ///
+    /// Exemptions are refreshed every `refresh` while [serving](WriteQueue::serve).
+    pub fn with_exemptions(mut self, exemptions: Arc<Exemptions>, refresh: Duration) -> Self {
+        self.exemptions = Some(exemptions);
+        self.refresh = refresh;
+        self
+    }
+
+    /// Forgets dids from `seen` when their write fails, so that sources write them again
+    /// next time they see them; failed removals mark them seen again.
+    pub fn with_seen(mut self, seen: SeenSet) -> Self {
+        self.seen = Some(seen);
+        self
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Writes queued dids into the output, or removes them, until every [`Writer`] is dropped.
+    ///
+    /// Writes failing on a transient error are retried with backoff. Other failed
+    /// writes are logged and skipped, and reported by the next flush of their class,
+    /// except on an expired session where the error is returned: the write is
+    /// retried on the next call.
+    pub async fn run<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &mut self,
+        agent: &BskyAgent<T, S>,
+        output: &impl Output,
+    ) -> Result<(), Box<dyn Error>> {
+        loop {
+            // writes given back after an expired session may have partly succeeded
+            let (priority, mut write, mut again) = match self.pending.take() {
+                Some((priority, write)) => (priority, write, true),
+                None => {
+                    let (priority, write) = select! {
+                        biased;
+                        Some(w) = self.live.recv() => (Priority::Live, w),
+                        Some(w) = self.reconcile.recv() => (Priority::Reconcile, w),
+                        Some(w) = self.backfill.recv() => (Priority::Backfill, w),
+                        else => return Ok(()),
+                    };
+                    if let Write::Flush(done) = write {
+                        let failed = self
+                            .metrics
+                            .class(priority)
+                            .unflushed
+                            .swap(0, Ordering::Relaxed);
+                        // the source may have given up waiting
+                        let _ = done.send(failed);
+                        continue;
+                    }
+                    self.metrics
+                        .class(priority)
+                        .queued
+                        .fetch_sub(write.dids().len(), Ordering::Relaxed);
+                    (priority, write, false)
+                }
+            };
+
+            let class = self.metrics.class(priority);
+            if let Some(exemptions) = &self.exemptions {
+                match &mut write {
+                    Write::Add(did) if exemptions.is_exempt(did) => {
+                        class.exempted.fetch_add(1, Ordering::Relaxed);
+                        info!(msg = "exempted, not adding", class = %priority, did = ?did);
+                        continue;
+                    }
+                    Write::AddBatch(dids) => {
+                        dids.retain(|did| {
+                            let exempt = exemptions.is_exempt(did);
+                            if exempt {
+                                class.exempted.fetch_add(1, Ordering::Relaxed);
+                                info!(msg = "exempted, not adding", class = %priority, did = ?did);
+                            }
+                            !exempt
+                        });
+                        if dids.is_empty() {
+                            continue;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            let mut retries = 0;
+            let res = loop {
+                // errors aren't Send: only the delay is kept across the sleep
+                let delay = {
+                    let res = match &write {
+                        Write::Add(did) if again => output
+                            .add_again(agent, did.clone())
+                            .await
+                            .map(|()| &class.written),
+                        Write::Add(did) => output
+                            .add(agent, did.clone())
+                            .await
+                            .map(|()| &class.written),
+                        // the batch may have been written before its response got lost
+                        Write::AddBatch(dids) if again => async {
+                            for did in dids {
+                                output.add_again(agent, did.clone()).await?;
+                            }
+                            Ok(())
+                        }
+                        .await
+                        .map(|()| &class.written),
+                        Write::AddBatch(dids) => output
+                            .add_batch(agent, dids.clone())
+                            .await
+                            .map(|()| &class.written),
+                        Write::Remove(did) => {
+                            output.remove(agent, did).await.map(|()| &class.removed)
+                        }
+                        Write::Flush(_) => unreachable!(),
+                    };
+                    match &res {
+                        Err(e) if retries < MAX_RETRIES && session::is_transient(e.as_ref()) => {
+                            let delay = RETRY_DELAY * 2u32.pow(retries);
+                            warn!(msg = "write failed, retrying", class = %priority, dids = ?write.dids(), err = %e, delay = ?delay);
+                            delay
+                        }
+                        _ => break res,
+                    }
+                };
+                retries += 1;
+                again = true;
+                time::sleep(delay).await;
+            };
+            let nb = write.dids().len();
+            match res {
+                Ok(counter) => {
+                    let before = counter.fetch_add(nb, Ordering::Relaxed);
+                    if (before + nb) / LOG_EVERY > before / LOG_EVERY {
+                        self.metrics.log();
+                    }
+                }
+                Err(e) if session::is_auth_error(e.as_ref()) => {
+                    self.pending = Some((priority, write));
+                    return Err(e);
+                }
+                Err(e) => {
+                    class.failed.fetch_add(nb, Ordering::Relaxed);
+                    class.unflushed.fetch_add(nb, Ordering::Relaxed);
+                    warn!(msg = "could not write to list", class = %priority, dids = ?write.dids(), err = %e);
+                    match (&self.seen, &write) {
+                        (Some(seen), Write::Add(_) | Write::AddBatch(_)) => {
+                            for did in write.dids() {
+                                seen.remove(did);
+                            }
+                        }
+                        (Some(seen), Write::Remove(did)) => _ = seen.insert(did),
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    /// Writes like [`WriteQueue::run`], logging in again with `credentials` when the session expires.
+    ///
+    /// Returns the error it stopped on, errors aren't Send.
+    pub async fn run_relogin<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &mut self,
+        agent: &BskyAgent<T, S>,
+        output: &impl Output,
+        credentials: Option<&Credentials>,
+    ) -> Result<(), String> {
+        loop {
+            let res = self
+                .run(agent, output)
+                .await
+                .map_err(|e| (session::is_auth_error(e.as_ref()), e.to_string()));
+            match res {
+                Err((true, _)) if relogin(agent, credentials).await => continue,
+                Err((_, e)) => return Err(e),
+                Ok(()) => return Ok(()),
+            }
+        }
+    }
+
+    /// Runs `sources` while writing what they queue into `output`, and refreshing exemptions,
+    /// until they're done or `token` is cancelled.
+    ///
+    /// Fails if writing stops first, e.g. once the session can't be renewed: sources
+    /// would wait forever on a full queue.
+    pub async fn serve<T: XrpcClient + Send + Sync, S: SessionStore + Send + Sync>(
+        &mut self,
+        agent: &BskyAgent<T, S>,
+        output: &impl Output,
+        credentials: Option<&Credentials>,
+        token: &CancellationToken,
+        sources: impl Future<Output = Result<(), String>>,
+    ) -> Result<(), String> {
+        let (exemptions, period) = (self.exemptions.clone(), self.refresh);
+        let refresh = async {
+            match &exemptions {
+                Some(e) => e.refresh_every(agent, period).await,
+                None => future::pending().await,
+            }
+        };
+        let metrics = self.metrics.clone();
+        select! {
+            res = sources => res,
+            _ = refresh => unreachable!(),
+            res = self.run_relogin(agent, output, credentials) => {
+                warn!(msg = "writer stopped");
+                metrics.log();
+                Err(match res {
+                    Ok(()) => "writer stopped".to_string(),
+                    Err(e) => format!("writer stopped: {e}"),
+                })
+            }
+            _ = token.cancelled() => {
+                info!(msg = "got cancellation");
+                metrics.log();
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Waits for ctrl-c, then cancels `token` and waits for `task` to stop.
+///
+/// A task stopping on its own, e.g. once its writer stopped, returns its result
+/// right away, so that the process doesn't keep running without writing anything.
+pub async fn until_ctrl_c(
+    mut task: JoinHandle<Result<(), String>>,
+    token: &CancellationToken,
+) -> Result<(), Box<dyn Error>> {
+    select! {
+        res = &mut task => Ok(res??),
+        res = signal::ctrl_c() => {
+            res?;
+            token.cancel();
+            Ok(task.await??)
+        }
+    }
+}